@@ -4,8 +4,9 @@ import { env } from '$env/dynamic/public'
 
 const DEV_HOST = () => env.PUBLIC_API_HOST ?? "127.0.0.1:8080"
 const ENDPOINT = () => "http://" + (dev ? DEV_HOST() : window.location.host);
-export function KERNEL_URI() {
-   return "ws://" + (dev ? DEV_HOST() : window.location.host) + "/kernel/socket";
+export function KERNEL_URI(params: Record<string, string> = {}) {
+   const query = Object.keys(params).length > 0 ? "?" + new URLSearchParams(params) : "";
+   return "ws://" + (dev ? DEV_HOST() : window.location.host) + "/kernel/socket" + query;
 }
 
 // server proxies REST api calls
@@ -13,14 +14,16 @@ export const api = {
 	get: (fn: string, params: Record<string, string>) => fetch(`${ENDPOINT()}/${fn}?` + new URLSearchParams(params), {
 		mode: 'cors',
 	}),
-	post: (fn: string, params: Record<string, string>) => fetch(`${ENDPOINT()}/${fn}`, {
+	post: (fn: string, params: Record<string, unknown>) => fetch(`${ENDPOINT()}/${fn}`, {
 		method: "POST",
 		body: JSON.stringify(params),
 		headers: {
 			"Content-Type": "application/json",
 		},
 		mode: 'cors',
-	})
+	}),
+	// server-sent events, see `notedag/watch`
+	watch: (fn: string, params: Record<string, string>) => new EventSource(`${ENDPOINT()}/${fn}?` + new URLSearchParams(params)),
 }
 
 export enum EditorMode {
@@ -2,6 +2,9 @@ import { v4 as uuidv4 } from 'uuid';
 
 export type UUID = string;
 
+/// .ind format version written by this frontend
+export const NOTEDAG_VERSION = 1;
+
 export interface CellInputState {
 	value: string;
 	syntax: string;
@@ -20,6 +23,8 @@ export interface CellOutputState {
 	result: string;
 	status: string;
 	executionCount: string;
+	/// what an `input()` in the running cell asks us to type
+	input?: { prompt: string, password: boolean };
 }
 
 function defaultCellOutput(): CellOutputState {
@@ -70,6 +75,17 @@ function defaultGroup(): GroupState {
 	}
 }
 
+export type KernelspecRef = {
+	name: string,
+	displayName: string,
+	language: string,
+}
+
+export type NoteDAGMetadata = {
+	kernelspec?: KernelspecRef,
+	[key: string]: any,
+}
+
 export class NoteDAGState {
 	_refresh?: () => void;
 
@@ -87,6 +103,9 @@ export class NoteDAGState {
 		public focusedGroup: UUID = root,
 		public focusedCell: UUID = groups[root].cells[0],
 		public activeGroupChain: GroupState[] = [],
+
+		/// Notebook level settings, e.g. the kernel it runs on
+		public metadata: NoteDAGMetadata = {},
 	) {
 		this.rebuildActiveGroupChain();
 	}
@@ -111,16 +130,40 @@ export class NoteDAGState {
 	static load(jsonStr: string, _refresh?: () => void): NoteDAGState {
 		try {
 			const json = JSON.parse(jsonStr);
-			let ret = new NoteDAGState(json.cells, json.groups, json.root);
+			let ret = new NoteDAGState(json.cells, json.groups, json.root, json.ui?.focusedGroup, json.ui?.focusedCell, [], json.metadata ?? {});
 			ret._refresh = _refresh;
 			return ret;
 		} catch (e) {
 			//console.error('failed to parse NoteDag from JSON:', e);
 			console.log('failed to parse NoteDAG from JSON'); 
+			// an empty file is a new notebook, anything else is worth telling the user about
+			if (jsonStr.trim() !== '') {
+				alert('failed to load notebook, saving will overwrite it. POST its path to /notedag/repair to recover a copy.');
+			}
 			return NoteDAGState.default();
 		}
 	}
 
+	/// serialized in the current .ind layout, see `migrate.rs` in the server
+	toJSON() {
+		return {
+			version: NOTEDAG_VERSION,
+			// a pending `input()` prompt belongs to the running kernel, not to the file
+			cells: Object.fromEntries(Object.entries(this.cells).map(([id, cell]) => {
+				const output = { ...cell.output };
+				delete output.input;
+				return [id, { ...cell, output }];
+			})),
+			groups: this.groups,
+			root: this.root,
+			ui: {
+				focusedGroup: this.focusedGroup,
+				focusedCell: this.focusedCell,
+			},
+			metadata: this.metadata,
+		}
+	}
+
 	refresh() {
 		if (!this._refresh) return false;
 		this._refresh.call(this);
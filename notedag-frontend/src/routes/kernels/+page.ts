@@ -0,0 +1,13 @@
+import { error } from '@sveltejs/kit';
+import { api } from '$lib';
+
+/** @type {import('./$types').PageLoad} */
+export async function load({ fetch }) {
+	const response = await api.get('kernel/running', {});
+	if (!response.ok) throw error(response.status, (await response.json()).message);
+	const kernels = await response.json();
+
+	return {
+		kernels,
+	}
+}
@@ -1,3 +1,4 @@
+import { error } from '@sveltejs/kit';
 import { api } from '$lib';
 
 /** @type {import('./$types').PageLoad} */
@@ -5,7 +6,9 @@ export async function load({ fetch, params }) {
 	const filePath = params.filePath;
 	
 	const response = await api.get('notedag/read', { filePath });
+	if (!response.ok) throw error(response.status, (await response.json()).message);
 	const contents = await response.json();
+	const revision = response.headers.get('ETag');
 
 	let tokens = filePath.split('/');
 	const filename = tokens[tokens.length-1];
@@ -13,5 +16,6 @@ export async function load({ fetch, params }) {
 		root: filePath,
 		filename,
 		contents,
+		revision,
 	}
 }
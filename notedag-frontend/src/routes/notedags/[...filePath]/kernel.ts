@@ -1,6 +1,14 @@
 import Convert from 'ansi-to-html';
-import type {CellState} from "$lib/notedag";
-import { KERNEL_URI } from '$lib';
+import type {CellOutputState, CellState, KernelspecRef} from "$lib/notedag";
+import { KERNEL_URI, api } from '$lib';
+import type { Completion, Inspection } from '$lib/components/CellInput.svelte';
+
+/// ms to wait before reconnecting a dropped websocket
+const RECONNECT_DELAY = 2000;
+
+/// the kernel counts in code points, javascript strings in UTF-16 code units
+const codePoints = (s: string, end: number) => [...s.slice(0, end)].length;
+const codeUnits = (s: string, end: number) => [...s].slice(0, end).join('').length;
 
 export class KernelManager {
 	connection = {
@@ -8,21 +16,51 @@ export class KernelManager {
 		status: 'disconnected',
 	};
 
+	/// the kernel the server started for us, once it is ready
+	kernelspec: KernelspecRef | null = null;
+	/// our ids in the session, a new one per connection. other clients have their own
+	users: Set<number> = new Set();
+	/// id of the session on the server, for the REST calls that take one
+	session: string | null = null;
+	/// `seq` of the last update from `session`, to get what we missed when reconnecting
+	lastSeq: number | null = null;
+	/// cells waiting for the kernel, in the order they will run
+	queue: { id: string, user: number }[] = [];
+	/// output that belongs to no cell, e.g. from a thread a finished cell started
+	notebookOutput: CellOutputState = { value: '', error: '', result: '', status: ' ', executionCount: '' };
+
 	callbacks: Record<string, (json: any) => void> = {};
 	refresh?: () => void;
+	onKernel?: (kernelspec: KernelspecRef) => void;
+	/// updates for cells someone else ran
+	onCellUpdate?: (json: any) => void;
+
+	/// notebook whose kernel we attach to, the server keeps it running across reconnects
+	filePath?: string;
+	/// kernelspec of the last `connect`, to reconnect with
+	kernel?: string;
 
-	constructor(refresh?: () => void) {
+	constructor(refresh?: () => void, onKernel?: (kernelspec: KernelspecRef) => void, filePath?: string) {
 		this.refresh = refresh;
+		this.onKernel = onKernel;
+		this.filePath = filePath;
 	}
 
-	async connect() {
+	/// `kernel` is a kernelspec name from `/kernel/list`, the server picks one if it is not given
+	async connect(kernel?: string) {
+		this.kernel = kernel;
 		await new Promise<void>((resolve, reject) => {
 			if (this.connection.ws !== null) {
 				resolve();
 				return;
 			} 
 
-			let ws = new WebSocket(KERNEL_URI());
+			const params: Record<string, string> = {};
+			if (kernel) params.kernel = kernel;
+			if (this.filePath) params.filePath = this.filePath;
+			if (this.session) params.session = this.session;
+			if (this.session && this.lastSeq !== null) params.since = String(this.lastSeq);
+			let ws = new WebSocket(KERNEL_URI(params));
 			this.connection = {
 				ws,
 				status: 'connecting',
@@ -35,29 +73,126 @@ export class KernelManager {
 				resolve();
 			};
 			ws.onclose = () => {
+				// the server tells us first when it does not want us back
+				const reconnect = !['shutdown', 'error'].includes(this.connection.status);
 				this.connection = {
 					ws: null,
 					status: 'disconnected'
 				};
 				console.log('disconnected');
 				if (this.refresh) this.refresh();
+				// the kernel is still running on the server, pick up where we left off
+				if (reconnect) setTimeout(() => this.connection.ws === null && this.connect(this.kernel), RECONNECT_DELAY);
 			};
 
 			ws.onmessage = (msg) => this.handleMessage(msg);
 		});
 	}
 
+	disconnect() {
+		const ws = this.connection.ws;
+		if (ws === null) return;
+		ws.onclose = null;
+		ws.close();
+		this.connection = {
+			ws: null,
+			status: 'disconnected'
+		};
+		this.kernelspec = null;
+		// the next connect may be for another kernel, which starts from scratch
+		this.session = null;
+		this.lastSeq = null;
+		if (this.refresh) this.refresh();
+	}
+
+	/// stop the running cell
+	interrupt() {
+		this.connection.ws?.send(JSON.stringify({ type: 'interrupt' }));
+	}
+
+	/// start over with a fresh interpreter, all variables are lost
+	restart() {
+		this.connection.ws?.send(JSON.stringify({ type: 'restart' }));
+	}
+
+	/// drop a cell that has not started running yet
+	cancel(id: string) {
+		this.connection.ws?.send(JSON.stringify({ type: 'cancelCell', id }));
+	}
+
+	/// move a cell that has not started running yet to `position` in the queue, counting from 1
+	moveCell(id: string, position: number) {
+		this.connection.ws?.send(JSON.stringify({ type: 'moveCell', id, position }));
+	}
+
+	/// drop every cell that has not started running yet
+	clearQueue() {
+		this.connection.ws?.send(JSON.stringify({ type: 'clearQueue' }));
+	}
+
+	/// answer what an `input()` in cell `id` asked for
+	inputReply(id: string, value: string) {
+		this.connection.ws?.send(JSON.stringify({ type: 'inputReply', id, value }));
+	}
+
+	/// tab completion from the live interpreter, null if the kernel cannot answer right now
+	async complete(code: string, pos: number): Promise<Completion | null> {
+		if (this.session === null) return null;
+		const response = await api.post('kernel/complete', { id: this.session, code, cursorPos: codePoints(code, pos) });
+		if (!response.ok) return null;
+		const json = await response.json();
+		return {
+			matches: json.matches,
+			from: codeUnits(code, json.cursorStart),
+			to: codeUnits(code, json.cursorEnd),
+		};
+	}
+
+	/// docstring of the name at `pos`, as plain text
+	async inspect(code: string, pos: number): Promise<Inspection | null> {
+		if (this.session === null) return null;
+		const response = await api.post('kernel/inspect', { id: this.session, code, cursorPos: codePoints(code, pos) });
+		if (!response.ok) return null;
+		const json = await response.json();
+		if (!json.found || !('text/plain' in json.data)) return null;
+		// tracebacks and docstrings come with ANSI colors
+		return { html: new Convert().toHtml(new Option(json.data['text/plain']).innerHTML) };
+	}
+
 	handleMessage(msg: MessageEvent<any>) {
 		//console.log('received', msg.data);
 		console.log('received message');
 
 		try {
 			const json = JSON.parse(msg.data);
+			if (json.seq !== undefined) this.lastSeq = json.seq;
 
-			if (json.id === undefined) {
+			if (json.channel === 'notebook') {
+				this.updateCell({ id: '', code: { value: '', syntax: 'code' }, output: this.notebookOutput }, json);
+				if (this.refresh) this.refresh();
+			} else if (json.status === 'queue') {
+				this.queue = json.queue;
+				if (this.refresh) this.refresh();
+			} else if (json.id === undefined) {
 				this.connection.status = json.status;
-			} else {
+				// someone may have queued cells before we got here
+				if (json.status === 'ready') this.connection.ws?.send(JSON.stringify({ type: 'listQueue' }));
+				if (json.status === 'error') alert(`kernel error: ${json.message}`);
+				if (json.user !== undefined) this.users.add(json.user);
+				if (json.session && json.session !== this.session) {
+					// a new session numbers its updates from the start
+					this.session = json.session;
+					this.lastSeq = null;
+				}
+				if (json.kernelspec) {
+					this.kernelspec = json.kernelspec;
+					if (this.onKernel) this.onKernel(json.kernelspec);
+				}
+				if (this.refresh) this.refresh();
+			} else if (this.users.has(json.user) && json.id in this.callbacks) {
 				this.callbacks[json.id](json);
+			} else if (this.onCellUpdate) {
+				this.onCellUpdate(json);
 			}
 		} catch (e) {
 			console.error('failed to parse ws message');
@@ -66,7 +201,7 @@ export class KernelManager {
 	};
 
 	updateCell(cell: CellState, json: any): [bool, CellState] {
-		const { id, name, value, status } = json;
+		const { id, name, value, status, user } = json;
 
 		const contentTypeHandler: Record<string, ((s: string) => string)> = {
 			'text/plain': (s: string) => {
@@ -118,20 +253,33 @@ export class KernelManager {
 				}
 				break;
 			case 'queued':
+				// position in the queue
+				cell.output.status = `:${value}`;
+				break;
 			case 'running':
 				cell.output.status = value;
 				break;
+			case 'input':
+			case 'password':
+				// only whoever ran the cell can answer
+				if (this.users.has(user)) cell.output.input = { prompt: value, password: name === 'password' };
+				break;
+			case 'cancelled':
+				cell.output.status = ' ';
+				return [true, cell];
 			case 'count':
 				cell.output.executionCount = value;
 				break;
 			case 'complete':
+				delete cell.output.input;
 				return [true, cell]
 		}
 
 		return [false, cell]
 	}
 
-	async submit(cell: CellState, callback: (cell: CellState) => void) {
+	/// `stopOnError` drops the cells queued after this one if it fails
+	async submit(cell: CellState, callback: (cell: CellState) => void, stopOnError: boolean = false) {
 		return new Promise((resolve, reject) => {
 			const ws = this.connection.ws;
 			if (!ws) reject('Not connected!');
@@ -146,8 +294,10 @@ export class KernelManager {
 			}
 
 			ws.send(JSON.stringify({
+				type: 'runCell',
 				id: cell.id,
 				value: cell.code.value,
+				stopOnError,
 			}));
 		});
 	}
@@ -1,3 +1,4 @@
+import { error } from '@sveltejs/kit';
 import { api } from '$lib';
 
 /** @type {import('./$types').PageLoad} */
@@ -5,6 +6,7 @@ export async function load({ fetch, params }) {
 	const filePath = params.filePath;
 
 	const response = await api.get('notedag/list', { filePath })
+	if (!response.ok) throw error(response.status, (await response.json()).message);
 	const files = await response.json();
 
 	return {
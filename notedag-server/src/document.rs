@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::migrate;
+
+pub type Uuid = String;
+
+/// Typed representation of an `.ind` notebook, mirroring `NoteDAGState` in the frontend.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    /// Format version, see `migrate::CURRENT_VERSION`
+    pub version: u64,
+
+    /// Smallest unit of code
+    pub cells: BTreeMap<Uuid, Cell>,
+
+    /// Groups cells together with some metadata
+    pub groups: BTreeMap<Uuid, Group>,
+
+    /// Entry point for execution
+    pub root: Uuid,
+
+    /// User state
+    #[serde(default)]
+    pub ui: UiState,
+
+    /// Notebook level settings, e.g. which kernel it runs on
+    #[serde(default)]
+    pub metadata: Metadata,
+
+    /// Keys we do not know about, e.g. written by a newer frontend. Kept so that saving through
+    /// the typed model does not drop them
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Like `metadata` in `.ipynb` files. Keys we do not know about are kept as they are.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernelspec: Option<KernelspecRef>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The kernel a notebook was last run with, so reopening it starts the same one.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KernelspecRef {
+    pub name: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub language: String,
+}
+
+/// Editor state that is persisted but does not affect execution.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UiState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focused_group: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focused_cell: Option<Uuid>,
+    /// see `Document::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Cell {
+    pub id: Uuid,
+    pub code: CellInput,
+    #[serde(default)]
+    pub meta: Map<String, Value>,
+    #[serde(default)]
+    pub output: CellOutput,
+    /// see `Document::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CellInput {
+    pub value: String,
+    pub syntax: String,
+    /// see `Document::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CellOutput {
+    pub value: String,
+    pub error: String,
+    pub result: String,
+    pub status: String,
+    pub execution_count: String,
+    /// see `Document::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for CellOutput {
+    fn default() -> Self {
+        CellOutput {
+            value: String::new(),
+            error: String::new(),
+            result: String::new(),
+            status: String::from(" "),
+            execution_count: String::new(),
+            extra: Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    pub id: Uuid,
+    pub name: String,
+    pub cells: Vec<Uuid>,
+    pub children: Vec<Uuid>,
+
+    /// User state
+    pub next_child: Option<Uuid>,
+
+    /// see `Document::extra`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Document {
+    /// Parses a notebook of any supported version, migrating it to the current layout.
+    pub fn parse(contents: &str) -> Result<Self, migrate::Error> {
+        let value = migrate::upgrade(contents)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn group(&self, group_id: &str) -> Option<&Group> {
+        self.groups.get(group_id)
+    }
+
+    pub fn cell(&self, cell_id: &str) -> Option<&Cell> {
+        self.cells.get(cell_id)
+    }
+
+    /// Group that lists `group_id` as one of its children.
+    pub fn parent(&self, group_id: &str) -> Option<&Group> {
+        self.groups
+            .values()
+            .find(|group| group.children.iter().any(|child| child == group_id))
+    }
+
+    /// Group that contains `cell_id`.
+    pub fn group_of_cell(&self, cell_id: &str) -> Option<&Group> {
+        self.groups
+            .values()
+            .find(|group| group.cells.iter().any(|cell| cell == cell_id))
+    }
+
+    /// Groups reached by following `nextChild` from the root, i.e. what the frontend renders.
+    pub fn active_chain(&self) -> Vec<&Group> {
+        let mut chain = vec![];
+        let mut seen = HashSet::new();
+        let mut id = Some(&self.root);
+        while let Some(group) = id.and_then(|id| self.groups.get(id)) {
+            // guard against cycles in malformed files
+            if !seen.insert(&group.id) {
+                break;
+            }
+            chain.push(group);
+            id = group.next_child.as_ref();
+        }
+        chain
+    }
+
+    /// Ancestors of `group_id`, starting from the root and excluding the group itself.
+    ///
+    /// Not used by the server itself, it is the path headless runs and exports need.
+    #[allow(dead_code)]
+    pub fn ancestors(&self, group_id: &str) -> Vec<&Group> {
+        let mut ancestors = vec![];
+        // guard against cycles in malformed files
+        let mut seen = HashSet::from([group_id]);
+        let mut id = group_id;
+        while let Some(parent) = self.parent(id) {
+            if !seen.insert(&parent.id) {
+                break;
+            }
+            ancestors.push(parent);
+            id = &parent.id;
+        }
+        ancestors.reverse();
+        ancestors
+    }
+}
+
+/// root -> a -> b along `nextChild`, with `c` as a second child of the root. `b` has the focus.
+#[cfg(test)]
+pub(crate) fn sample() -> Document {
+    use serde_json::json;
+
+    let cell = |id: &str| json!({ "id": id, "code": { "value": "", "syntax": "code" } });
+    let group = |id: &str, cells: &[&str], children: &[&str]| {
+        json!({ "id": id, "name": id, "cells": cells, "children": children, "nextChild": children.first() })
+    };
+    let doc = json!({
+        "version": migrate::CURRENT_VERSION,
+        "cells": { "c1": cell("c1"), "c2": cell("c2"), "c3": cell("c3") },
+        "groups": {
+            "root": group("root", &["c1"], &["a", "c"]),
+            "a": group("a", &["c2"], &["b"]),
+            "b": group("b", &["c3"], &[]),
+            "c": group("c", &[], &[]),
+        },
+        "root": "root",
+        "ui": { "focusedGroup": "b", "focusedCell": "c3" },
+    });
+    Document::parse(&doc.to_string()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const EXAMPLE: &str = include_str!("../../tests/refactor_example.ind");
+
+    fn ids(groups: Vec<&Group>) -> Vec<&str> {
+        groups.into_iter().map(|group| group.id.as_str()).collect()
+    }
+
+    #[test]
+    fn parent() {
+        let doc = sample();
+        assert_eq!(doc.parent("a").map(|group| group.id.as_str()), Some("root"));
+        assert_eq!(doc.parent("b").map(|group| group.id.as_str()), Some("a"));
+        assert!(doc.parent("root").is_none());
+        assert!(doc.parent("missing").is_none());
+    }
+
+    #[test]
+    fn group_of_cell() {
+        let doc = sample();
+        assert_eq!(doc.group_of_cell("c1").map(|group| group.id.as_str()), Some("root"));
+        assert_eq!(doc.group_of_cell("c3").map(|group| group.id.as_str()), Some("b"));
+        assert!(doc.group_of_cell("missing").is_none());
+    }
+
+    #[test]
+    fn ancestors() {
+        let doc = sample();
+        assert_eq!(ids(doc.ancestors("b")), ["root", "a"]);
+        assert_eq!(ids(doc.ancestors("c")), ["root"]);
+        assert!(doc.ancestors("root").is_empty());
+        assert!(doc.ancestors("missing").is_empty());
+    }
+
+    #[test]
+    fn ancestors_stop_at_cycles() {
+        let mut doc = sample();
+        // b -> a -> b, besides root -> a
+        doc.groups.get_mut("b").unwrap().children.push("a".into());
+        let ancestors = ids(doc.ancestors("b"));
+        assert_eq!(ancestors.last(), Some(&"a"));
+        assert!(!ancestors.contains(&"b"));
+    }
+
+    #[test]
+    fn active_chain_follows_next_child() {
+        let mut doc = sample();
+        assert_eq!(ids(doc.active_chain()), ["root", "a", "b"]);
+
+        doc.groups.get_mut("root").unwrap().next_child = Some("c".into());
+        assert_eq!(ids(doc.active_chain()), ["root", "c"]);
+    }
+
+    #[test]
+    fn active_chain_stops_at_cycles() {
+        let mut doc = sample();
+        let b = doc.groups.get_mut("b").unwrap();
+        b.children.push("a".into());
+        b.next_child = Some("a".into());
+        assert_eq!(ids(doc.active_chain()), ["root", "a", "b"]);
+    }
+
+    #[test]
+    fn active_chain_without_root() {
+        let mut doc = sample();
+        doc.root = "missing".into();
+        assert!(doc.active_chain().is_empty());
+    }
+
+    #[test]
+    fn example_round_trips() {
+        let doc = Document::parse(EXAMPLE).unwrap();
+        assert_eq!(doc.cells.len(), 11);
+        assert_eq!(doc.groups.len(), 6);
+        // the chain the file was saved with, before version 1 derived it from `nextChild`
+        let original: Value = serde_json::from_str(EXAMPLE).unwrap();
+        let saved_chain: Vec<&str> = original["activeGroupChain"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| group["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids(doc.active_chain()), saved_chain);
+
+        let saved = doc.to_json().unwrap();
+        let mut expected = migrate::upgrade(EXAMPLE).unwrap();
+        // the only thing saving adds, the file predates notebook metadata
+        expected["metadata"] = json!({});
+        assert_eq!(serde_json::from_str::<Value>(&saved).unwrap(), expected);
+        assert_eq!(Document::parse(&saved).unwrap().to_json().unwrap(), saved);
+    }
+
+    #[test]
+    fn unknown_fields_survive() {
+        let mut value = migrate::upgrade(EXAMPLE).unwrap();
+        value["metadata"] = json!({ "kernelspec": { "name": "python3", "displayName": "", "language": "" }, "author": "x" });
+        value["future"] = json!({ "a": 1 });
+        let cell = value["cells"].as_object_mut().unwrap().values_mut().next().unwrap();
+        cell["tags"] = json!(["slow"]);
+        cell["output"]["mimeBundle"] = json!({});
+        let group = value["groups"].as_object_mut().unwrap().values_mut().next().unwrap();
+        group["collapsed"] = json!(true);
+        value["ui"]["scroll"] = json!(120);
+
+        let saved = Document::parse(&value.to_string()).unwrap().to_json().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&saved).unwrap(), value);
+    }
+}
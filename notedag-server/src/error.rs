@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use crate::models::ErrorMessage;
+use crate::sandbox;
+
+/// Everything a request can fail with. Handlers reject with this and `handle_rejection`
+/// turns it into a JSON `ErrorMessage`.
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    /// e.g. a kernel that is no longer running
+    NotFound(String),
+    /// outside of `ROOT`, or the filesystem said no
+    Forbidden(String),
+    /// the notebook changed since the client loaded it
+    Conflict {
+        message: String,
+        current_revision: Option<String>,
+    },
+    PayloadTooLarge {
+        limit: u64,
+    },
+    /// shutting down, or out of capacity
+    Unavailable(String),
+    /// filesystem error on a client supplied path
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    Internal(String),
+}
+
+impl Error {
+    /// Attaches the client facing `path` to a filesystem error.
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Error::Io { path: path.into(), source }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Conflict { .. } => StatusCode::CONFLICT,
+            Error::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Io { source, .. } => match source.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                io::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+                io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine readable counterpart of `status`, for the frontend to switch on.
+    pub fn code(&self) -> &'static str {
+        match self.status() {
+            StatusCode::BAD_REQUEST => "bad_request",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::CONFLICT => "conflict",
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+            _ => "internal",
+        }
+    }
+
+    pub fn details(&self) -> Value {
+        match self {
+            Error::Conflict { current_revision, .. } => json!({ "currentRevision": current_revision }),
+            Error::PayloadTooLarge { limit } => json!({ "limit": limit }),
+            Error::Io { path, source } => json!({ "path": path, "kind": format!("{:?}", source.kind()) }),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn to_message(&self) -> ErrorMessage {
+        ErrorMessage {
+            code: self.code().into(),
+            message: self.to_string(),
+            details: self.details(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadRequest(message)
+            | Error::NotFound(message)
+            | Error::Forbidden(message)
+            | Error::Conflict { message, .. }
+            | Error::Unavailable(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+            Error::PayloadTooLarge { limit } => write!(f, "request body exceeds the limit of {} bytes", limit),
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl warp::reject::Reject for Error {}
+
+impl From<sandbox::Error> for Error {
+    fn from(e: sandbox::Error) -> Self {
+        match e {
+            sandbox::Error::Io(e) => Error::Internal(e.to_string()),
+            e => Error::Forbidden(e.to_string()),
+        }
+    }
+}
+
+/// Turns our own rejections, and the warp ones a client can trigger, into JSON errors.
+/// Anything else (mostly unmatched routes) falls through to warp.
+pub async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    let error = if let Some(e) = err.find::<Error>() {
+        if e.status().is_server_error() {
+            error!("{}", e);
+        }
+        e.to_message()
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        Error::BadRequest(e.to_string()).to_message()
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        Error::BadRequest(e.to_string()).to_message()
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        Error::BadRequest(e.to_string()).to_message()
+    } else {
+        return Err(err);
+    };
+
+    let status = err.find::<Error>().map(Error::status).unwrap_or(StatusCode::BAD_REQUEST);
+    Ok(warp::reply::with_status(warp::reply::json(&error), status))
+}
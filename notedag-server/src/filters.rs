@@ -1,10 +1,27 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
 use warp::Filter;
 
+use crate::error;
+
 pub fn api(
     notify_shutdown: tokio::sync::broadcast::Receiver<()>,
     shutdown_complete_tx: tokio::sync::mpsc::Sender<()>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    checkhealth().or(notedag::main()).or(kernel::main(notify_shutdown, shutdown_complete_tx))
+    checkhealth()
+        .or(notedag::main(notify_shutdown.resubscribe()))
+        .or(kernel::main(notify_shutdown, shutdown_complete_tx))
+        .recover(error::handle_rejection)
+}
+
+/// Hands each request its own subscription to the shutdown notification, for long lived
+/// responses that would otherwise keep the server from exiting.
+fn with_shutdown(
+    notify_shutdown: broadcast::Receiver<()>,
+) -> impl Filter<Extract = (broadcast::Receiver<()>,), Error = std::convert::Infallible> + Clone {
+    let notify_shutdown = Arc::new(notify_shutdown);
+    warp::any().map(move || notify_shutdown.resubscribe())
 }
 
 fn checkhealth() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
@@ -14,14 +31,37 @@ fn checkhealth() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rej
 }
 
 mod notedag {
+    use crate::error::Error;
     use crate::handlers;
     use crate::models;
+    use futures_util::{Stream, StreamExt};
     use serde::de::DeserializeOwned;
+    use std::env;
+    use tokio::sync::broadcast;
+    use warp::hyper::body::Buf;
     use warp::Filter;
 
+    /// Notebooks with saved outputs (plots, dataframes) get large quickly
+    const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
 
-    pub fn main() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path("notedag").and(list().or(create()).or(read()).or(write()))
+
+    pub fn main(
+        notify_shutdown: broadcast::Receiver<()>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("notedag").and(
+            list()
+                .or(create())
+                .or(read())
+                .or(write())
+                .or(validate())
+                .or(repair())
+                .or(rename())
+                .or(move_())
+                .or(copy())
+                .or(mkdir())
+                .or(delete())
+                .or(watch(notify_shutdown)),
+        )
     }
 
     fn list() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
@@ -48,57 +88,152 @@ mod notedag {
     fn write() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path!("write")
             .and(warp::post())
+            .and(warp::header::optional::<String>("if-match"))
             .and(json_body())
             .and_then(handlers::write)
     }
 
-    fn json_body<T: Send + DeserializeOwned>(
+    fn validate() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("validate")
+            .and(warp::get())
+            .and(warp::query::<models::ValidateOptions>())
+            .and_then(handlers::validate)
+    }
+
+    fn repair() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("repair")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::repair)
+    }
+
+    fn rename() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("rename")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::rename)
+    }
+
+    fn move_() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("move")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::move_)
+    }
+
+    fn copy() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("copy")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::copy)
+    }
+
+    fn mkdir() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("mkdir")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::mkdir)
+    }
+
+    fn delete() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("delete")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::delete)
+    }
+
+    fn watch(
+        notify_shutdown: broadcast::Receiver<()>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("watch")
+            .and(warp::get())
+            .and(warp::query::<models::ListOptions>())
+            .and(super::with_shutdown(notify_shutdown))
+            .and_then(handlers::watch)
+    }
+
+    pub(super) fn json_body<T: Send + DeserializeOwned>(
     ) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
         // When accepting a body, we want a JSON body
         // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+        let limit = max_body_size();
+        warp::header::optional::<u64>("content-length")
+            .and(warp::body::stream())
+            .and_then(move |length, body| read_json(limit, length, body))
+    }
+
+    /// Maximum request body size in bytes, configured through `MAX_BODY_SIZE`.
+    fn max_body_size() -> u64 {
+        env::var("MAX_BODY_SIZE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Reads the body chunk by chunk so chunked uploads without a `Content-Length`
+    /// are held to the same limit.
+    async fn read_json<T: DeserializeOwned>(
+        limit: u64,
+        length: Option<u64>,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    ) -> Result<T, warp::Rejection> {
+        if length.is_some_and(|length| length > limit) {
+            return Err(Error::PayloadTooLarge { limit }.into());
+        }
+
+        let mut bytes = Vec::with_capacity(length.unwrap_or_default() as usize);
+        futures_util::pin_mut!(body);
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk.map_err(|e| Error::BadRequest(format!("invalid request body: {}", e)))?;
+            let size = chunk.remaining();
+            if (bytes.len() + size) as u64 > limit {
+                return Err(Error::PayloadTooLarge { limit }.into());
+            }
+            bytes.extend_from_slice(&chunk.copy_to_bytes(size));
+        }
+
+        serde_json::from_slice(&bytes).map_err(|e| Error::BadRequest(format!("invalid request body: {}", e)).into())
     }
 }
 
 mod kernel {
+    use super::notedag::json_body;
+    use crate::error::Error;
     use crate::handlers;
 
-    use crate::kernel::Kernel;
-    use crate::kernel::KernelConnection;
-    use crate::kernel::KernelSpec;
+    use crate::models::KernelOptions;
+    use crate::models::KernelRequest;
     use crate::models::KernelUpdate;
-    use crate::models::RunCell;
-    use crate::models::RunCellUpdate;
-    use jupyter_client::responses::ExecutionState;
+    use crate::session::{self, Session};
     use warp::Filter;
 
-    use std::collections::HashMap;
     use std::sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     };
 
     use futures_util::{SinkExt, StreamExt, TryFutureExt};
-    use tokio::sync::{mpsc, Mutex, RwLock};
+    use tokio::sync::{broadcast, mpsc};
     use tokio_stream::wrappers::UnboundedReceiverStream;
     use warp::ws::{Message, WebSocket};
 
-    use jupyter_client::responses::{IoPubResponse, Response};
-
     /// Our global unique user id counter.
     static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-    /// Our state of currently connected users.
-    ///
-    /// - Key is their id
-    /// - Value is a sender of `warp::ws::Message`
-    type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
-
     pub fn main(
         notify_shutdown: tokio::sync::broadcast::Receiver<()>,
         shutdown_complete_tx: tokio::sync::mpsc::Sender<()>,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path("kernel").and(list().or(socket(notify_shutdown, shutdown_complete_tx)))
+        warp::path("kernel").and(
+            list()
+                .or(running())
+                .or(shutdown())
+                .or(interrupt())
+                .or(restart())
+                .or(complete())
+                .or(inspect())
+                .or(socket(notify_shutdown, shutdown_complete_tx)),
+        )
     }
 
     fn list() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
@@ -107,31 +242,96 @@ mod kernel {
             .and_then(handlers::list_kernels)
     }
 
+    fn running() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("running")
+            .and(warp::get())
+            .and_then(handlers::running_kernels)
+    }
+
+    fn shutdown() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("shutdown")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::shutdown_kernel)
+    }
+
+    fn interrupt() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("interrupt")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::interrupt_kernel)
+    }
+
+    fn restart() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("restart")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::restart_kernel)
+    }
+
+    fn complete() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("complete")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::complete_code)
+    }
+
+    fn inspect() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("inspect")
+            .and(warp::post())
+            .and(json_body())
+            .and_then(handlers::inspect_code)
+    }
+
+    /// Lets a connection notice the server going down, and keeps the server up until the
+    /// connection said goodbye to its client.
+    struct Shutdown {
+        notify: broadcast::Receiver<()>,
+        /// `main` waits until every one of these is dropped
+        _complete: mpsc::Sender<()>,
+    }
+
     // GET /kernel/socket -> websocket upgrade
     pub fn socket(
-        _notify_shutdown: tokio::sync::broadcast::Receiver<()>,
-        _shutdown_complete_tx: tokio::sync::mpsc::Sender<()>,
+        notify_shutdown: tokio::sync::broadcast::Receiver<()>,
+        shutdown_complete_tx: tokio::sync::mpsc::Sender<()>,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        // Keep track of all connected users, key is usize, value
-        // is a websocket sender.
-        let users = Users::default();
-        // Turn our "state" into a new Filter...
-        let users = warp::any().map(move || users.clone());
+        // every connection gets a clone of the sender. ours goes away once shutdown starts, so
+        // `main` only waits for the connections
+        let shutdown_complete_tx = Arc::new(std::sync::Mutex::new(Some(shutdown_complete_tx)));
+        let mut notify = notify_shutdown.resubscribe();
+        let tx = Arc::clone(&shutdown_complete_tx);
+        tokio::spawn(async move {
+            let _ = notify.recv().await;
+            tx.lock().unwrap().take();
+        });
 
+        let shutdown = super::with_shutdown(notify_shutdown).and_then(move |notify| {
+            let complete = shutdown_complete_tx.lock().unwrap().clone();
+            async move {
+                match complete {
+                    Some(complete) => Ok(Shutdown { notify, _complete: complete }),
+                    // already shutting down
+                    None => Err(warp::reject::custom(Error::Unavailable("the server is shutting down".into()))),
+                }
+            }
+        });
 
         warp::path("socket")
             // The `ws()` filter will prepare Websocket handshake...
             .and(warp::ws())
-            .and(users)
-            .map(|ws: warp::ws::Ws, users| {
+            .and(warp::query::<KernelOptions>())
+            .and(shutdown)
+            .map(|ws: warp::ws::Ws, options: KernelOptions, shutdown| {
                 // This will call our function if the handshake succeeds.
-                ws.on_upgrade(move |socket| user_connected(socket, users))
+                ws.on_upgrade(move |socket| user_connected(socket, options, shutdown))
             })
     }
 
     async fn user_connected(
         ws: WebSocket,
-        users: Users,
+        options: KernelOptions,
+        mut shutdown: Shutdown,
     ) {
         // Use a counter to assign a new unique ID for this user.
         let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
@@ -146,143 +346,79 @@ mod kernel {
         let (tx, rx) = mpsc::unbounded_channel();
         let mut rx = UnboundedReceiverStream::new(rx);
 
-        {
-            let tx = tx.clone();
-            tokio::task::spawn(async move {
-                while let Some(message) = rx.next().await {
-                    user_ws_tx
-                        .send(message)
-                        .unwrap_or_else(|e| {
-                            eprintln!("websocket send error: {}", e);
-                        })
-                        .await;
-                }
-            });
-
-            // Save the sender in our list of connected users.
-            users.write().await.insert(my_id, tx);
-        }
+        tokio::task::spawn(async move {
+            while let Some(message) = rx.next().await {
+                user_ws_tx
+                    .send(message)
+                    .unwrap_or_else(|e| {
+                        eprintln!("websocket send error: {}", e);
+                    })
+                    .await;
+            }
+        });
 
         // Return a `Future` that is basically a state machine managing
         // this specific user's connection.
-        let spec: Vec<KernelSpec> = KernelSpec::get_available_kernels().unwrap();
-        let kernel = Kernel::start(&spec[0]).await.unwrap();
-
-        let conn = kernel.connect().await.unwrap();
-        let new_msg = serde_json::to_string(&KernelUpdate { status: "ready".into() }).unwrap();
-        tx.send(Message::text(new_msg)).unwrap();        
-
-        // Set up the heartbeat watcher
-        let hb_receiver = conn.client.heartbeat().unwrap();
-        std::thread::spawn(move || {
-            for _ in hb_receiver {
-                debug!("Received heartbeat from kernel");
+        let _ = tx.send(KernelUpdate { status: "starting".into(), ..Default::default() }.into());
+        let session = match session::open(&options).await {
+            Ok(session) => session,
+            Err(message) => {
+                warn!("user {}: {}", my_id, message);
+                let _ = tx.send(KernelUpdate::error(message).into());
+                let _ = tx.send(Message::close());
+                user_disconnected(my_id);
+                return;
             }
-        });
-
-        // Spawn an IOPub watcher
-        {
-            let tx = tx.clone();
-            let last_run_cell = Arc::clone(&conn.last_run_cell);
-            let receiver = conn.client.iopub_subscribe().unwrap();
-            std::thread::spawn(move || {
-                for msg in receiver {
-                    //println!("Received message from kernel: {:#?}", msg);
-                    if let Response::IoPub(response) = msg {
-                        let opt = last_run_cell.read().unwrap();
-                        let output = opt.as_ref().and_then(|run_cell| {
-                            //dbg!(&response);
-                            dbg!("received IoPub response");
-                            match response {
-                                IoPubResponse::Stream { content, .. } => Some(RunCellUpdate {
-                                    id: run_cell.id.clone(),
-                                    name: String::from("output"),
-                                    value: content.text,
-                                }),
-                                IoPubResponse::Error { content, .. } => {
-                                    Some(RunCellUpdate {
-                                        id: run_cell.id.clone(),
-                                        name: String::from("error"),
-                                        value: content.traceback.join("\n"),
-                                    })
-                                },
-                                IoPubResponse::Status { content, .. } => {
-                                    println!("status update: {:?}", content);
-                                    match content.execution_state {
-                                        ExecutionState::Busy => None,
-                                        ExecutionState::Idle => Some(RunCellUpdate {
-                                            id: run_cell.id.clone(),
-                                            name: String::from("complete"),
-                                            value: String::from(""),
-                                        }),
-                                        _ => None,
-                                    }
-                                },
-                                IoPubResponse::ExecuteInput { .. } => {
-                                    Some(RunCellUpdate {
-                                        id: run_cell.id.clone(),
-                                        name: String::from("running"),
-                                        value: "*".into(),
-                                    })
-                                },
-                                IoPubResponse::ExecuteResult { content, .. } => {
-                                    Some(RunCellUpdate {
-                                        id: run_cell.id.clone(),
-                                        name: String::from("result"),
-                                        value: serde_json::to_string(&content.data).unwrap()
-                                    })
-                                },
-                                IoPubResponse::DisplayData { content, .. } => {
-                                    Some(RunCellUpdate {
-                                        id: run_cell.id.clone(),
-                                        name: String::from("data"),
-                                        value: serde_json::to_string(&content.data).unwrap()
-                                    })
-                                },
-                                _ => None
-                            }
-                        });
-
-                        if let Some(thing) = output {
-                            tx.send(thing.into()).unwrap();
-                        }
-                    }
+        };
+        let _ = tx.send(KernelUpdate {
+            status: "ready".into(),
+            kernelspec: Some(session.spec.to_ref()),
+            session: Some(session.id.clone()),
+            user: Some(my_id),
+            ..Default::default()
+        }.into());
+        // from here on we hear about every run in the session, including those of others.
+        // a `since` from some other session would skip updates of this one
+        let since = options.since.filter(|_| options.session.as_ref() == Some(&session.id));
+        session.attach(my_id, tx.clone(), since);
+
+        loop {
+            let result = tokio::select! {
+                result = user_ws_rx.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = shutdown.notify.recv() => {
+                    let _ = tx.send(KernelUpdate {
+                        status: "shutdown".into(),
+                        message: Some("the server is shutting down".into()),
+                        ..Default::default()
+                    }.into());
+                    let _ = tx.send(Message::close());
+                    break;
                 }
-            });
+            };
+            let msg = match result {
+                Ok(msg) => msg,
+                Err(e) => {
+                    eprintln!("websocket error(uid={}): {}", my_id, e);
+                    break;
+                }
+            };
+            user_message(my_id, msg, &tx, Arc::clone(&session)).await;
         }
 
-        {
-            let conn = Arc::new(Mutex::new(conn));
-
-            while let Some(result) = user_ws_rx.next().await {
-                let msg = match result {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        eprintln!("websocket error(uid={}): {}", my_id, e);
-                        break;
-                    }
-                };
-                user_message(
-                    my_id,
-                    msg,
-                    tx.clone(),
-                    Arc::clone(&conn),
-                )
-                .await;
-            }
-        }
+        // the kernel keeps running for when the user comes back
+        session.detach(my_id);
 
         // user_ws_rx stream will keep processing as long as the user stays
         // connected. Once they disconnect, then...
-        user_disconnected(my_id, &users).await;
+        user_disconnected(my_id);
     }
 
-    async fn user_message(
-        my_id: usize,
-        msg: Message,
-        tx: mpsc::UnboundedSender<Message>,
-        conn: Arc<Mutex<KernelConnection>>,
-    ) {
+    /// Handles a request from user `my_id`. Errors in the request only go back to `tx`, the
+    /// other clients of the session did nothing wrong.
+    async fn user_message(my_id: usize, msg: Message, tx: &mpsc::UnboundedSender<Message>, session: Arc<Session>) {
         // Skip any non-Text messages...
         let msg = if let Ok(s) = msg.to_str() {
             s
@@ -291,32 +427,50 @@ mod kernel {
         };
         println!("received from {}: {}", my_id, msg);
 
-        let run_cell: RunCell = serde_json::from_str(msg).unwrap();
-
-        // Send update to subscriber
-        let _ = tx.send(RunCellUpdate {
-            id: run_cell.id.clone(),
-            name: String::from("queued"),
-            value: ":".into(),
-        }.into());
+        let request: KernelRequest = match serde_json::from_str(msg) {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = tx.send(KernelUpdate::error(format!("invalid message: {}", e)).into());
+                return;
+            }
+        };
 
-        // This is a slow but blocking step, so we're going to toss it into a tokio spawn.
-        tokio::task::spawn(async move {
-            let conn = conn.lock().await;
-            let execution_count = conn.run_cell(run_cell.clone()).unwrap();
-
-            let _ = tx.send(RunCellUpdate {
-                id: run_cell.id.clone(),
-                name: String::from("count"),
-                value: execution_count.to_string(),
-            }.into());
-        });
+        match request {
+            KernelRequest::RunCell(mut run_cell) => {
+                run_cell.user = my_id;
+                session.touch();
+                // runs once the cells queued before it are done
+                session.enqueue(run_cell);
+            }
+            KernelRequest::ListQueue => session.send_queue(),
+            KernelRequest::CancelCell { id } => {
+                if !session.cancel(&id) {
+                    let _ = tx.send(KernelUpdate::error(format!("cell {} is not queued", id)).into());
+                }
+            }
+            KernelRequest::ClearQueue => session.clear_queue("cancelled"),
+            KernelRequest::MoveCell { id, position } => {
+                if !session.move_cell(&id, position) {
+                    let _ = tx.send(KernelUpdate::error(format!("cell {} is not queued", id)).into());
+                }
+            }
+            KernelRequest::InputReply { id, value } => {
+                if let Err(message) = session.reply_input(my_id, &id, value) {
+                    let _ = tx.send(KernelUpdate::error(message).into());
+                }
+            }
+            KernelRequest::Interrupt => {
+                let _ = session.interrupt().await;
+            }
+            KernelRequest::Restart => {
+                tokio::task::spawn(async move {
+                    let _ = session.restart().await;
+                });
+            }
+        }
     }
 
-    async fn user_disconnected(my_id: usize, users: &Users) {
+    fn user_disconnected(my_id: usize) {
         eprintln!("good bye user: {}", my_id);
-
-        // Stream closed up, so remove from the user list
-        users.write().await.remove(&my_id);
     }
 }
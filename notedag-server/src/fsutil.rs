@@ -0,0 +1,116 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Replaces the contents of `path` without ever leaving a partially written file behind.
+///
+/// The data goes to a temporary file in the same directory, which is synced and then renamed
+/// over `path`. If `path` already exists its permissions and ownership carry over.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // write through symlinks instead of replacing them
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = fs::metadata(&path).ok();
+
+    let tmp_path = tmp_path(&path)?;
+    let result = write_tmp(&tmp_path, contents, original.as_ref()).and_then(|_| fs::rename(&tmp_path, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // make the rename itself durable
+    fs::File::open(parent(&path))?.sync_all()
+}
+
+/// Revision of a file for optimistic concurrency: its mtime plus a hash of its contents.
+///
+/// The hash catches writes within the mtime resolution, the mtime catches a file that was
+/// rewritten with identical contents by someone else (which we treat as a change, to be safe).
+pub fn revision(contents: &[u8], metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let hash = Sha256::digest(contents);
+    let hash: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{:x}-{}", modified, hash)
+}
+
+/// Revision of the file at `path`, or `None` if it does not exist.
+pub fn current_revision(path: &Path) -> io::Result<Option<String>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(revision(&contents, &fs::metadata(path)?))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_tmp(tmp_path: &Path, contents: &[u8], original: Option<&fs::Metadata>) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    if let Some(original) = original {
+        // never more open than the original, not even while the contents are being written
+        options.mode(original.permissions().mode() & 0o7777);
+    }
+    let mut file = options.open(tmp_path)?;
+    file.write_all(contents)?;
+
+    if let Some(original) = original {
+        file.set_permissions(original.permissions())?;
+        if let Err(e) = std::os::unix::fs::fchown(&file, Some(original.uid()), Some(original.gid())) {
+            // only root may give files away, so this is expected when saving someone else's notebook
+            warn!("could not keep ownership of {:?}: {}", tmp_path, e);
+        }
+    }
+
+    file.sync_all()
+}
+
+/// Whether `path` is one of the temporary files `write_atomic` leaves around while saving.
+pub fn is_tmp_path(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(".tmp"))
+}
+
+/// `dir/name` -> `dir/.name.<uuid>.tmp`
+fn tmp_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let tmp_name = format!(".{}.{}.tmp", file_name.to_string_lossy(), Uuid::new_v4());
+    Ok(parent(path).join(tmp_name))
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_permissions() {
+        let path = std::env::temp_dir().join(format!("notedag-fsutil-{}.ind", Uuid::new_v4()));
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomic(&path, b"{\"version\": 1}").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, "{\"version\": 1}");
+    }
+}
@@ -1,83 +1,495 @@
-use std::ffi::OsString;
 use std::fs;
+use std::io;
 
 use std::convert::Infallible;
-use std::env;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
+use futures_util::StreamExt;
+use tokio::sync::{broadcast, Mutex};
+use warp::http::header::ETAG;
 
+use crate::document::Document;
+use crate::error::Error;
+use crate::fsutil;
 use crate::kernel::KernelSpec;
+use crate::migrate;
+use crate::models::DeleteOptions;
+use crate::models::KernelId;
+use crate::models::CompleteRequest;
+use crate::models::InspectRequest;
+use crate::models::DeleteResult;
 use crate::models::ListItem;
 use crate::models::ListOptions;
+use crate::models::MoveOptions;
 use crate::models::NoteDAG;
 use crate::models::NoteDAGWrite;
+use crate::models::NoteDAGWritten;
+use crate::models::RenameOptions;
+use crate::models::ValidateOptions;
+use crate::models::ValidationReport;
+use crate::sandbox;
+use crate::session;
+use crate::validate;
+use crate::watch;
 
-fn get_path(file_path: &str) -> String {
-    let root = env::var_os("ROOT")
-        .map(OsString::into_string)
-        .transpose()
-        .unwrap()
-        .unwrap_or_default();
-    format!("{}{}", root, file_path)
+fn get_path(file_path: &str) -> Result<PathBuf, Error> {
+    sandbox::get().resolve(file_path).map_err(|e| {
+        println!("Refusing {}: {}", file_path, e);
+        Error::from(e)
+    })
 }
 
-pub async fn list(options: ListOptions) -> Result<impl warp::Reply, Infallible> {
+pub async fn list(options: ListOptions) -> Result<impl warp::Reply, warp::Rejection> {
     let dir = options.file_path.unwrap_or_default();
-    let paths = fs::read_dir(get_path(&dir)).unwrap();
+    let dir_path = get_path(&dir)?;
+    let paths = fs::read_dir(&dir_path).map_err(|e| Error::io(&dir, e))?;
 
     println!("Listing {}", dir);
     let mut files = vec![];
     for path in paths {
         println!("Name: {:?}", path);
 
-        let f = path.unwrap();
-        let metadata = f.metadata().unwrap();
+        let f = path.map_err(|e| Error::io(&dir, e))?;
+        let file_name = f.file_name();
+        // entries can vanish between listing and stat, or be unreadable to us
+        let metadata = match f.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Skipping {:?}: {}", f.path(), e);
+                continue;
+            }
+        };
 
-        let file_name = f.file_name().into_string().unwrap();
-        let file_path = f.path().into_os_string().into_string().unwrap();
-        let is_dir = f.file_type().unwrap().is_dir();
-        let size = metadata.len();
-        let modified = metadata.modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-        files.push(ListItem {
-            file_name,
-            file_path,
-            is_dir,
-            size,
-            modified,
-        });
+        if dir_path == sandbox::get().root() && file_name == TRASH_DIR {
+            continue;
+        }
+        files.push(list_item(&f.path(), &metadata));
     }
 
     Ok(warp::reply::json(&files))
 }
 
-pub async fn create(notedag: NoteDAG) -> Result<impl warp::Reply, Infallible> {
-    let path = get_path(&notedag.file_path);
-    let file = fs::File::create(path).unwrap();
-    println!("Created {:?}", file);
-    Ok(warp::reply())
+/// Server-sent events for changes to the entries of a directory, see `watch::watch`.
+pub async fn watch(
+    options: ListOptions,
+    mut notify_shutdown: broadcast::Receiver<()>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let dir = options.file_path.unwrap_or_default();
+    let path = get_path(&dir)?;
+    let stream = watch::watch(&path).map_err(|e| match e.kind {
+        notify::ErrorKind::Io(e) => Error::io(&dir, e),
+        notify::ErrorKind::PathNotFound => Error::io(&dir, io::ErrorKind::NotFound.into()),
+        _ => Error::Internal(format!("cannot watch {}: {}", dir, e)),
+    })?;
+    // the server waits for open responses before exiting, so end the stream on shutdown
+    let stream = stream.take_until(async move {
+        let _ = notify_shutdown.recv().await;
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+pub fn list_item(path: &Path, metadata: &fs::Metadata) -> ListItem {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_path = sandbox::get().relative(path);
+    let is_dir = metadata.is_dir();
+    let size = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    ListItem {
+        file_name,
+        file_path,
+        is_dir,
+        size,
+        modified,
+    }
 }
 
-pub async fn read(notedag: NoteDAG) -> Result<impl warp::Reply, Infallible> {
-    let path = get_path(&notedag.file_path);
-    let contents = fs::read_to_string(&path).unwrap();
-    println!("Read {}", path);
-    Ok(warp::reply::json(&contents))
+fn stat(file_path: &str, path: &Path) -> Result<ListItem, Error> {
+    let metadata = fs::metadata(path).map_err(|e| Error::io(file_path, e))?;
+    Ok(list_item(path, &metadata))
 }
 
-pub async fn write(notedag: NoteDAGWrite) -> Result<impl warp::Reply, Infallible> {
-    let path = get_path(&notedag.file_path);
-    let _ = fs::OpenOptions::new()
-        .write(true)
-        .open(&path)
-        .unwrap()
-        .write_all(&notedag.contents.into_bytes())
-        .unwrap();
-    println!("Wrote {}", path);
+pub async fn create(notedag: NoteDAG) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = get_path(&notedag.file_path)?;
+    fsutil::write_atomic(&path, b"").map_err(|e| Error::io(&notedag.file_path, e))?;
+    println!("Created {:?}", path);
     Ok(warp::reply())
 }
 
+pub async fn read(notedag: NoteDAG) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = get_path(&notedag.file_path)?;
+    let mut contents = fs::read_to_string(&path).map_err(|e| Error::io(&notedag.file_path, e))?;
+    let metadata = fs::metadata(&path).map_err(|e| Error::io(&notedag.file_path, e))?;
+    let revision = fsutil::revision(contents.as_bytes(), &metadata);
+    println!("Read {:?} at {}", path, revision);
+
+    // hand out the current layout, the upgrade is persisted on the next save.
+    // new files are empty and anything unreadable is passed through as is
+    if !contents.trim().is_empty() {
+        match migrate::upgrade(&contents) {
+            Ok(value) => contents = value.to_string(),
+            Err(e) => println!("Not migrating {:?}: {}", path, e),
+        }
+    }
+    let reply = warp::reply::json(&contents);
+    Ok(warp::reply::with_header(reply, ETAG, etag(&revision)))
+}
+
+pub async fn write(if_match: Option<String>, notedag: NoteDAGWrite) -> Result<impl warp::Reply, warp::Rejection> {
+    // refuse to persist anything we would not be able to load again,
+    // including documents written by a newer version of notedag
+    let doc = Document::parse(&notedag.contents).map_err(|e| Error::BadRequest(format!("invalid notedag: {}", e)))?;
+    let contents = doc.to_json().map_err(|e| Error::Internal(e.to_string()))?;
+
+    let path = get_path(&notedag.file_path)?;
+
+    // hold the lock from comparing revisions until the rename so two saves cannot interleave
+    let _guard = WRITE_LOCK.lock().await;
+    let current_revision = fsutil::current_revision(&path).map_err(|e| Error::io(&notedag.file_path, e))?;
+    check_revision(if_match.as_deref(), notedag.base_revision.as_deref(), current_revision)
+        .inspect_err(|e| println!("Rejected write to {:?}: {}", path, e.details()))?;
+
+    fsutil::write_atomic(&path, contents.as_bytes()).map_err(|e| Error::io(&notedag.file_path, e))?;
+    let revision = fsutil::current_revision(&path)
+        .map_err(|e| Error::io(&notedag.file_path, e))?
+        .ok_or_else(|| Error::Internal(format!("{} vanished after writing it", notedag.file_path)))?;
+    println!("Wrote {:?} at {}", path, revision);
+
+    let reply = warp::reply::json(&NoteDAGWritten { revision: revision.clone() });
+    Ok(warp::reply::with_header(reply, ETAG, etag(&revision)))
+}
+
+/// Renames within the same directory.
+pub async fn rename(options: RenameOptions) -> Result<impl warp::Reply, warp::Rejection> {
+    if options.new_name.is_empty() || options.new_name.contains('/') || options.new_name == ".." {
+        return Err(Error::BadRequest(format!("{} is not a valid file name", options.new_name)).into());
+    }
+    let to = match options.file_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, options.new_name),
+        None => options.new_name.clone(),
+    };
+    Ok(warp::reply::json(&move_path(&options.file_path, &to)?))
+}
+
+pub async fn move_(options: MoveOptions) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&move_path(&options.from, &options.to)?))
+}
+
+fn move_path(from: &str, to: &str) -> Result<ListItem, Error> {
+    let from_path = get_path(from)?;
+    let to_path = get_path(to)?;
+    if from_path == sandbox::get().root() {
+        return Err(Error::BadRequest(String::from("cannot move the root directory")));
+    }
+    // fs::rename happily replaces files, we never want to lose one that way
+    if to_path.symlink_metadata().is_ok() {
+        return Err(Error::io(to, io::Error::new(io::ErrorKind::AlreadyExists, "already exists")));
+    }
+
+    fs::rename(&from_path, &to_path).map_err(|e| Error::io(from, e))?;
+    println!("Moved {:?} to {:?}", from_path, to_path);
+    stat(to, &to_path)
+}
+
+/// Duplicates a notebook, directories are not copied.
+pub async fn copy(options: MoveOptions) -> Result<impl warp::Reply, warp::Rejection> {
+    let from_path = get_path(&options.from)?;
+    let to_path = get_path(&options.to)?;
+    if from_path.is_dir() {
+        return Err(Error::BadRequest(format!("{} is a directory", options.from)).into());
+    }
+    if to_path.symlink_metadata().is_ok() {
+        return Err(Error::io(&options.to, io::Error::new(io::ErrorKind::AlreadyExists, "already exists")).into());
+    }
+
+    let contents = fs::read(&from_path).map_err(|e| Error::io(&options.from, e))?;
+    fsutil::write_atomic(&to_path, &contents).map_err(|e| Error::io(&options.to, e))?;
+    println!("Copied {:?} to {:?}", from_path, to_path);
+    Ok(warp::reply::json(&stat(&options.to, &to_path)?))
+}
+
+pub async fn mkdir(notedag: NoteDAG) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = get_path(&notedag.file_path)?;
+    fs::create_dir(&path).map_err(|e| Error::io(&notedag.file_path, e))?;
+    println!("Created directory {:?}", path);
+    Ok(warp::reply::json(&stat(&notedag.file_path, &path)?))
+}
+
+/// Moves a file or directory into `ROOT/.trash`, or removes it for good with `permanent`.
+pub async fn delete(options: DeleteOptions) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = get_path(&options.file_path)?;
+    let root = sandbox::get().root();
+    let trash = root.join(TRASH_DIR);
+    if path == root || path == trash {
+        return Err(Error::BadRequest(format!("cannot delete {:?}", options.file_path)).into());
+    }
+    let metadata = path.symlink_metadata().map_err(|e| Error::io(&options.file_path, e))?;
+
+    if options.permanent || path.starts_with(&trash) {
+        let result = if metadata.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.map_err(|e| Error::io(&options.file_path, e))?;
+        println!("Deleted {:?}", path);
+        return Ok(warp::reply::json(&DeleteResult { trash_path: None }));
+    }
+
+    fs::create_dir_all(&trash).map_err(|e| Error::io(TRASH_DIR, e))?;
+    let stamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let trash_path = trash.join(format!("{}-{}", stamp, name));
+    fs::rename(&path, &trash_path).map_err(|e| Error::io(&options.file_path, e))?;
+    println!("Trashed {:?} to {:?}", path, trash_path);
+
+    let trash_path = Some(sandbox::get().relative(&trash_path));
+    Ok(warp::reply::json(&DeleteResult { trash_path }))
+}
+
+/// Where `delete` puts things, relative to `ROOT`. Hidden from the root listing.
+pub const TRASH_DIR: &str = ".trash";
+
+/// Serializes revision checks and writes across requests.
+static WRITE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Waits for saves in progress to reach the disk, for a clean exit.
+pub async fn flush_writes() {
+    let _guard = WRITE_LOCK.lock().await;
+}
+
+fn etag(revision: &str) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// Compares the revision a save started from with `current_revision`, `None` for a missing file.
+///
+/// `If-Match` wins over `base_revision`, a stale one of either is a 409 with the current
+/// revision. Without either the save goes through unconditionally.
+fn check_revision(if_match: Option<&str>, base_revision: Option<&str>, current_revision: Option<String>) -> Result<(), Error> {
+    let base = match if_match.map(parse_etag).or(base_revision) {
+        Some(base) => base,
+        None => return Ok(()),
+    };
+    let matches = match &current_revision {
+        Some(current) => base == "*" || base == current,
+        None => false,
+    };
+    if matches {
+        return Ok(());
+    }
+
+    Err(Error::Conflict {
+        message: String::from("notebook was changed since it was loaded"),
+        current_revision,
+    })
+}
+
+/// Accepts both strong and weak entity tags, `*` passes through.
+fn parse_etag(etag: &str) -> &str {
+    let etag = etag.trim();
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    etag.trim_matches('"')
+}
+
+/// Reports what is wrong with a notebook, see `repair` for fixing it.
+pub async fn validate(options: ValidateOptions) -> Result<impl warp::Reply, warp::Rejection> {
+    if options.repair {
+        let message = String::from("repairing writes a file, POST to /notedag/repair instead");
+        return Err(Error::BadRequest(message).into());
+    }
+    let doc = match parse_for_validation(&options.file_path)? {
+        Ok(doc) => doc,
+        Err(report) => return Ok(warp::reply::json(&report)),
+    };
+    let issues = validate::validate(&doc);
+    Ok(warp::reply::json(&ValidationReport { issues, repaired_path: None }))
+}
+
+/// Writes a repaired copy of a notebook next to the original, if it has any issues.
+pub async fn repair(notedag: NoteDAG) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut doc = match parse_for_validation(&notedag.file_path)? {
+        Ok(doc) => doc,
+        Err(report) => return Ok(warp::reply::json(&report)),
+    };
+
+    let issues = validate::repair(&mut doc);
+    let repaired_path = if issues.is_empty() {
+        None
+    } else {
+        let repaired_path = repaired_path(&notedag.file_path);
+        let contents = doc.to_json().map_err(|e| Error::Internal(e.to_string()))?;
+        fsutil::write_atomic(&get_path(&repaired_path)?, contents.as_bytes())
+            .map_err(|e| Error::io(&repaired_path, e))?;
+        println!("Wrote repaired copy {}", repaired_path);
+        Some(repaired_path)
+    };
+    Ok(warp::reply::json(&ValidationReport { issues, repaired_path }))
+}
+
+/// Reads the notebook at `file_path`, or the report for one that does not parse at all.
+fn parse_for_validation(file_path: &str) -> Result<Result<Document, ValidationReport>, Error> {
+    let path = get_path(file_path)?;
+    let contents = fs::read_to_string(&path).map_err(|e| Error::io(file_path, e))?;
+    println!("Validating {:?}", path);
+
+    Ok(Document::parse(&contents).map_err(|e| {
+        // nothing to repair if we cannot even read the structure
+        let issues = vec![validate::Issue::Unparseable { message: e.to_string() }];
+        ValidationReport { issues, repaired_path: None }
+    }))
+}
+
+/// `notes/foo.ind` -> `notes/foo.repaired.ind`
+fn repaired_path(file_path: &str) -> String {
+    match file_path.strip_suffix(".ind") {
+        Some(stem) => format!("{}.repaired.ind", stem),
+        None => format!("{}.repaired", file_path),
+    }
+}
+
 pub async fn list_kernels() -> Result<impl warp::Reply, Infallible> {
     let kernels = KernelSpec::get_available_kernels().unwrap_or_else(|_| vec![]);
     Ok(warp::reply::json(&kernels))
 }
 
+pub async fn running_kernels() -> Result<impl warp::Reply, Infallible> {
+    let mut kernels = vec![];
+    for session in session::list() {
+        kernels.push(session.info().await);
+    }
+    Ok(warp::reply::json(&kernels))
+}
+
+pub async fn shutdown_kernel(kernel: KernelId) -> Result<impl warp::Reply, warp::Rejection> {
+    if !session::shutdown(&kernel.id, "the kernel was shut down").await {
+        return Err(no_such_kernel(&kernel.id).into());
+    }
+    Ok(warp::reply())
+}
+
+pub async fn interrupt_kernel(kernel: KernelId) -> Result<impl warp::Reply, warp::Rejection> {
+    let session = session::get(&kernel.id).ok_or_else(|| no_such_kernel(&kernel.id))?;
+    session.interrupt().await.map_err(Error::Internal)?;
+    Ok(warp::reply())
+}
+
+pub async fn restart_kernel(kernel: KernelId) -> Result<impl warp::Reply, warp::Rejection> {
+    let session = session::get(&kernel.id).ok_or_else(|| no_such_kernel(&kernel.id))?;
+    session.restart().await.map_err(Error::Internal)?;
+    Ok(warp::reply())
+}
+
+pub async fn complete_code(request: CompleteRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let session = session::get(&request.id).ok_or_else(|| no_such_kernel(&request.id))?;
+    let completion = session
+        .complete(request.code, request.cursor_pos)
+        .await
+        .map_err(Error::Unavailable)?;
+    Ok(warp::reply::json(&completion))
+}
+
+pub async fn inspect_code(request: InspectRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let session = session::get(&request.id).ok_or_else(|| no_such_kernel(&request.id))?;
+    let inspection = session
+        .inspect(request.code, request.cursor_pos, request.detail)
+        .await
+        .map_err(Error::Unavailable)?;
+    Ok(warp::reply::json(&inspection))
+}
+
+fn no_such_kernel(id: &str) -> Error {
+    Error::NotFound(format!("no running kernel {}", id))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    /// `check_revision` against a real file, rewritten after we took `revision`.
+    fn stale() -> (String, Option<String>) {
+        let path = std::env::temp_dir().join(format!("notedag-handlers-{}.ind", uuid::Uuid::new_v4()));
+        fsutil::write_atomic(&path, b"{}").unwrap();
+        let revision = fsutil::current_revision(&path).unwrap().unwrap();
+        fsutil::write_atomic(&path, b"{\"version\": 1}").unwrap();
+        let current = fsutil::current_revision(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (revision, current)
+    }
+
+    fn status(result: Result<(), Error>) -> StatusCode {
+        result.unwrap_err().status()
+    }
+
+    #[test]
+    fn etag_round_trips() {
+        assert_eq!(etag("1-ab"), "\"1-ab\"");
+        assert_eq!(parse_etag(&etag("1-ab")), "1-ab");
+        assert_eq!(parse_etag(" W/\"1-ab\" "), "1-ab");
+        assert_eq!(parse_etag("*"), "*");
+    }
+
+    #[test]
+    fn unconditional() {
+        assert!(check_revision(None, None, None).is_ok());
+        assert!(check_revision(None, None, Some("1-ab".into())).is_ok());
+    }
+
+    #[test]
+    fn matching_revision() {
+        let current = Some(String::from("1-ab"));
+        assert!(check_revision(Some("\"1-ab\""), None, current.clone()).is_ok());
+        assert!(check_revision(Some("W/\"1-ab\""), None, current.clone()).is_ok());
+        assert!(check_revision(None, Some("1-ab"), current.clone()).is_ok());
+        assert!(check_revision(Some("*"), None, current).is_ok());
+    }
+
+    #[test]
+    fn stale_if_match() {
+        let (revision, current) = stale();
+        assert_ne!(Some(&revision), current.as_ref());
+
+        let error = check_revision(Some(&etag(&revision)), None, current.clone()).unwrap_err();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "conflict");
+        assert_eq!(error.details()["currentRevision"], current.unwrap());
+    }
+
+    #[test]
+    fn stale_base_revision() {
+        let (revision, current) = stale();
+
+        let error = check_revision(None, Some(&revision), current.clone()).unwrap_err();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "conflict");
+        assert_eq!(error.details()["currentRevision"], current.unwrap());
+    }
+
+    #[test]
+    fn if_match_wins_over_base_revision() {
+        let current = Some(String::from("1-ab"));
+        assert!(check_revision(Some("\"1-ab\""), Some("0-00"), current.clone()).is_ok());
+        assert_eq!(status(check_revision(Some("\"0-00\""), Some("1-ab"), current)), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn missing_file() {
+        // even `*` needs the file to exist
+        assert_eq!(status(check_revision(Some("*"), None, None)), StatusCode::CONFLICT);
+        assert_eq!(status(check_revision(None, Some("1-ab"), None)), StatusCode::CONFLICT);
+        let error = check_revision(None, Some("1-ab"), None).unwrap_err();
+        assert!(error.details()["currentRevision"].is_null());
+    }
+}
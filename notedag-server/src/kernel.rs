@@ -1,6 +1,10 @@
-use std::{process, collections::HashMap, fs};
-use jupyter_client::{Client, commands::Command, responses::{Response, ShellResponse}};
-use serde::Serialize;
+use std::{process, collections::{BTreeMap, HashMap, VecDeque}, env, fs, io, path::{Path, PathBuf}, time::Duration};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use tokio::time::Instant;
+use jupyter_client::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use uuid::Uuid;
 
 use std::sync::{
@@ -8,58 +12,470 @@ use std::sync::{
     Arc,
 };
 
+use crate::document::KernelspecRef;
 use crate::models::RunCell;
+use crate::wire;
 
+/// A kernel we know how to start, read from a Jupyter `kernels/<name>/kernel.json`.
 #[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct KernelSpec {
-    pub cmd: String,
-    pub args: Vec<String>,
+    /// name of the kernelspec directory, which is how clients pick a kernel
+    pub name: String,
+    /// command line, with `{connection_file}` and `{resource_dir}` still to be filled in
+    pub argv: Vec<String>,
+    pub display_name: String,
+    pub language: String,
+    pub env: HashMap<String, String>,
+    pub interrupt_mode: InterruptMode,
+    #[serde(skip)]
+    pub resource_dir: PathBuf,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InterruptMode {
+    /// send SIGINT to the kernel process
+    #[default]
+    Signal,
+    /// send an `interrupt_request` on the control channel
+    Message,
+}
+
+/// `kernel.json` as written by `jupyter kernelspec install`.
+#[derive(Deserialize)]
+struct KernelJson {
+    argv: Vec<String>,
+    display_name: String,
+    #[serde(default)]
+    language: String,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    interrupt_mode: InterruptMode,
+}
+
+/// `connection_file` as described in the Jupyter messaging docs, written by us before the kernel starts.
+#[derive(Serialize, Deserialize)]
+struct ConnectionInfo {
+    transport: String,
+    ip: String,
+    shell_port: u16,
+    iopub_port: u16,
+    stdin_port: u16,
+    control_port: u16,
+    hb_port: u16,
+    signature_scheme: String,
+    /// HMAC key, anyone who has it can run code in the kernel
+    key: String,
+    #[serde(default)]
+    kernel_name: String,
+}
+
+/// Started when a client does not ask for a particular kernel.
+const DEFAULT_KERNEL: &str = "python3";
+
+/// Seconds to wait for a kernel to come up, see `startup_timeout`.
+const DEFAULT_STARTUP_TIMEOUT: u64 = 60;
+
+/// How long a kernel gets to shut down after `shutdown_request`, and again after SIGTERM.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Cells whose late output, e.g. from threads they started, still goes to them.
+const ROUTED_LIMIT: usize = 1000;
+
+/// How long a kernel gets to answer `interrupt_request`.
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check on a starting kernel.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Kernels only listen on the loopback interface.
+const LOCALHOST: &str = "127.0.0.1";
+
+/// How long `sweep_connection_files` waits for a heartbeat port to accept a connection.
+const ALIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 impl KernelSpec {
+    /// All kernelspecs installed in the Jupyter data directories, sorted by name.
+    ///
+    /// Like `jupyter kernelspec list`, a name found in an earlier directory of `kernel_dirs`
+    /// shadows the same name further down. Falls back to plain `ipython` if nothing is installed.
     pub fn get_available_kernels() -> Result<Vec<Self>> {
-        Ok(vec![
-           KernelSpec {
-               cmd: "ipython".into(),
-               args: ["kernel".into(), "-f".into()].into(),
-           }
-        ])
+        let mut specs = BTreeMap::new();
+        for dir in kernel_dirs() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if specs.contains_key(&name) || !entry.path().join("kernel.json").is_file() {
+                    continue;
+                }
+                match Self::from_dir(&name, &entry.path()) {
+                    Ok(spec) => {
+                        specs.insert(name, spec);
+                    }
+                    Err(e) => warn!("skipping kernelspec {:?}: {}", entry.path(), e),
+                }
+            }
+        }
+
+        if specs.is_empty() {
+            warn!("no kernelspecs found, falling back to ipython");
+            return Ok(vec![Self::ipython()]);
+        }
+        Ok(specs.into_values().collect())
+    }
+
+    /// The kernelspec called `name`, or a default one if no name was asked for.
+    pub fn find(name: Option<&str>) -> Result<Option<Self>> {
+        let mut specs = Self::get_available_kernels()?;
+        let index = match name {
+            Some(name) => specs.iter().position(|spec| spec.name == name),
+            None => specs
+                .iter()
+                .position(|spec| spec.name == DEFAULT_KERNEL)
+                .or((!specs.is_empty()).then_some(0)),
+        };
+        Ok(index.map(|index| specs.swap_remove(index)))
+    }
+
+    /// What a notebook records about the kernel it uses.
+    pub fn to_ref(&self) -> KernelspecRef {
+        KernelspecRef {
+            name: self.name.clone(),
+            display_name: self.display_name.clone(),
+            language: self.language.clone(),
+        }
+    }
+
+    fn from_dir(name: &str, dir: &Path) -> Result<Self> {
+        let file = fs::File::open(dir.join("kernel.json"))?;
+        let json: KernelJson = serde_json::from_reader(file)?;
+        if json.argv.is_empty() {
+            return Err("argv is empty".into());
+        }
+        Ok(KernelSpec {
+            name: name.into(),
+            argv: json.argv,
+            display_name: json.display_name,
+            language: json.language,
+            env: json.env,
+            interrupt_mode: json.interrupt_mode,
+            resource_dir: dir.to_path_buf(),
+        })
+    }
+
+    fn ipython() -> Self {
+        KernelSpec {
+            name: DEFAULT_KERNEL.into(),
+            argv: ["ipython", "kernel", "-f", "{connection_file}"].map(String::from).into(),
+            display_name: "Python 3 (ipython)".into(),
+            language: "python".into(),
+            env: HashMap::new(),
+            interrupt_mode: InterruptMode::Signal,
+            resource_dir: PathBuf::new(),
+        }
+    }
+
+    /// The command line to start this kernel with `connection_file`.
+    fn command(&self, connection_file: &str) -> Vec<String> {
+        let resource_dir = self.resource_dir.to_string_lossy();
+        self.argv
+            .iter()
+            .map(|arg| {
+                arg.replace("{connection_file}", connection_file)
+                    .replace("{resource_dir}", &resource_dir)
+            })
+            .collect()
+    }
+}
+
+impl ConnectionInfo {
+    /// Free ports on localhost and a random key.
+    fn new(kernel_name: &str) -> io::Result<Self> {
+        // keep every listener open until all ports are picked, so none is handed out twice.
+        // the kernel binds them again right after, like jupyter_client does
+        let listeners = (0..5)
+            .map(|_| TcpListener::bind((LOCALHOST, 0)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let ports = listeners
+            .iter()
+            .map(|listener| Ok(listener.local_addr()?.port()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(ConnectionInfo {
+            transport: "tcp".into(),
+            ip: LOCALHOST.into(),
+            shell_port: ports[0],
+            iopub_port: ports[1],
+            stdin_port: ports[2],
+            control_port: ports[3],
+            hb_port: ports[4],
+            signature_scheme: "hmac-sha256".into(),
+            key: Uuid::new_v4().simple().to_string(),
+            kernel_name: kernel_name.into(),
+        })
+    }
+
+    /// Writes a new file at `path` that only we can read.
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Connects a new channel to `port`, see `wire::Channel::connect`.
+    fn channel(&self, port: u16, session: &str) -> wire::Result<wire::Channel> {
+        wire::Channel::connect(&format!("{}://{}:{}", self.transport, self.ip, port), &self.key, session)
+    }
+
+    /// Whether some kernel still answers on the heartbeat port.
+    fn is_alive(&self) -> bool {
+        match format!("{}:{}", self.ip, self.hb_port).parse::<SocketAddr>() {
+            Ok(addr) => TcpStream::connect_timeout(&addr, ALIVE_TIMEOUT).is_ok(),
+            // not something we wrote, leave it alone
+            Err(_) => true,
+        }
+    }
+}
+
+/// Removes connection files left behind by kernels that are no longer running, e.g. after a crash.
+///
+/// The runtime dir is shared with other Jupyter servers, so only files nothing answers on are removed.
+pub fn sweep_connection_files() {
+    let dir = match runtime_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            warn!("no runtime dir for connection files: {}", e);
+            return;
+        }
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("failed to list {:?}: {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("kernel-") || !name.ends_with(".json") {
+            continue;
+        }
+        let path = entry.path();
+        // unreadable files may still be in the middle of being written
+        let info = fs::File::open(&path)
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, ConnectionInfo>(file).ok());
+        if info.is_none_or(|info| info.transport != "tcp" || info.is_alive()) {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => info!("removed stale connection file {:?}", path),
+            Err(e) => warn!("failed to remove stale connection file {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Where connection files go: `JUPYTER_RUNTIME_DIR`, `$XDG_RUNTIME_DIR/jupyter`, or `runtime` in
+/// the user data dir like `jupyter --runtime-dir`. Created readable by us only if it is missing.
+fn runtime_dir() -> io::Result<PathBuf> {
+    let dir = env::var_os("JUPYTER_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("XDG_RUNTIME_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| PathBuf::from(dir).join("jupyter"))
+        })
+        .or_else(|| data_dir().map(|dir| dir.join("runtime")))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "set HOME or JUPYTER_RUNTIME_DIR"))?;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// The user's Jupyter data dir, `JUPYTER_DATA_DIR` or the platform default.
+fn data_dir() -> Option<PathBuf> {
+    if let Some(data_dir) = env::var_os("JUPYTER_DATA_DIR").filter(|dir| !dir.is_empty()) {
+        return Some(data_dir.into());
+    }
+    let home = PathBuf::from(env::var_os("HOME").filter(|home| !home.is_empty())?);
+    if cfg!(target_os = "macos") {
+        return Some(home.join("Library/Jupyter"));
+    }
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".local/share"));
+    Some(data_home.join("jupyter"))
+}
+
+/// `kernels` directories in the order Jupyter searches them: `JUPYTER_PATH`, the user data dir,
+/// the data dirs of the active virtualenv or conda env (`sys.prefix`), then the system ones.
+fn kernel_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::var_os("JUPYTER_PATH")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default();
+
+    dirs.extend(data_dir());
+
+    for prefix in ["VIRTUAL_ENV", "CONDA_PREFIX"] {
+        if let Some(prefix) = env::var_os(prefix).filter(|prefix| !prefix.is_empty()) {
+            dirs.push(PathBuf::from(prefix).join("share/jupyter"));
+        }
+    }
+
+    dirs.push("/usr/local/share/jupyter".into());
+    dirs.push("/usr/share/jupyter".into());
+
+    dirs.into_iter().map(|dir| dir.join("kernels")).collect()
+}
+
+/// How an `execute_request` went.
+pub struct ExecuteReply {
+    pub execution_count: i64,
+    /// the code raised, or the kernel aborted it
+    pub failed: bool,
+}
+
+/// Cells sent to the kernel, by the msg_id of their `execute_request`.
+///
+/// Output is routed by its parent msg_id, so late output, e.g. from threads a cell started, still
+/// goes to the cell. Only the last `ROUTED_LIMIT` cells are kept.
+#[derive(Default)]
+pub struct SentCells {
+    cells: HashMap<String, RunCell>,
+    /// msg_ids oldest first, to forget them again
+    order: VecDeque<String>,
+}
+
+impl SentCells {
+    fn insert(&mut self, msg_id: String, run_cell: RunCell) {
+        if self.order.len() == ROUTED_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.cells.remove(&oldest);
+            }
+        }
+        self.order.push_back(msg_id.clone());
+        self.cells.insert(msg_id, run_cell);
+    }
+
+    pub fn get(&self, msg_id: &str) -> Option<&RunCell> {
+        self.cells.get(msg_id)
     }
 }
 
 pub struct KernelConnection {
+    /// for IOPub and the heartbeat
     pub client: Client,
-    pub last_run_cell: Arc<sync::RwLock<Option<RunCell>>>,
+    /// where `run_cell` sends its `execute_request`s
+    shell: wire::Channel,
+    /// where the kernel asks for what `input()` reads, on the same session as `shell`
+    stdin: wire::Channel,
+    pub sent: Arc<sync::Mutex<SentCells>>,
 }
 
-impl KernelConnection {
-    pub fn run_cell(&self, run_cell: RunCell) -> Result<i64> {
-        // update last_run_cell
-        let mut r = self.last_run_cell.write().unwrap();
-        *r = Some(run_cell.clone());
+/// How `run_cell` gets the lines that `input()` and `getpass()` in a cell ask for.
+pub trait Input {
+    /// Asks whoever ran `run_cell` for a line, not to be echoed if `password`.
+    fn ask(&self, run_cell: &RunCell, prompt: &str, password: bool);
+    /// The answer to the last `ask`, checked every `POLL_INTERVAL` until it is not `Pending`.
+    fn answer(&self) -> Answer;
+}
+
+pub enum Answer {
+    Pending,
+    Line(String),
+    /// nobody is going to answer, the cell gets an EOFError
+    Gone,
+}
 
+impl KernelConnection {
+    /// Runs `run_cell` and waits for its `execute_reply`.
+    ///
+    /// A kernel that dies mid-cell never replies, so `gone` is checked every `POLL_INTERVAL`
+    /// and the wait ends with an error once it says the kernel is gone or being stopped.
+    pub fn run_cell(
+        &self,
+        run_cell: RunCell,
+        input: &impl Input,
+        gone: impl Fn() -> bool,
+    ) -> Result<ExecuteReply> {
         // submit code to kernel
         println!("submitting: {}", run_cell.value);
 
-        // Command to run
-        let command = Command::Execute {
-            code: run_cell.value.to_string(),
-            silent: false,
-            store_history: true,
-            user_expressions: HashMap::new(),
-            allow_stdin: true,
-            stop_on_error: false,
-        };
+        let request = self.shell.header("execute_request");
+        let content = json!({
+            "code": run_cell.value,
+            "silent": false,
+            "store_history": true,
+            "user_expressions": {},
+            "allow_stdin": true,
+            "stop_on_error": run_cell.stop_on_error,
+        });
+        // before sending, so the IOPub watcher knows the cell by the time its output arrives
+        self.sent.lock().unwrap().insert(request.msg_id.clone(), run_cell.clone());
+        self.shell.send(&request, None, &content)?;
 
-        // Run some code on the kernel - slow step
-        let response = self.client.send_shell_command(command);
+        // Wait for the kernel to run it, answering its input_requests along the way - slow step
+        let mut asking = None;
+        let reply = loop {
+            if gone() {
+                return Err("the kernel stopped before the cell finished".into());
+            }
+            let ready = wire::poll(&[&self.shell, &self.stdin], POLL_INTERVAL)?;
+            if ready[1] {
+                match self.stdin.recv(Duration::ZERO)? {
+                    Some(message)
+                        if message.header.msg_type == "input_request"
+                            && message.parent_id.as_ref() == Some(&request.msg_id) =>
+                    {
+                        let prompt = message.content["prompt"].as_str().unwrap_or_default();
+                        let password = message.content["password"].as_bool().unwrap_or_default();
+                        input.ask(&run_cell, prompt, password);
+                        asking = Some(message.header);
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(parent) = &asking {
+                let value = match input.answer() {
+                    Answer::Pending => None,
+                    Answer::Line(line) => Some(line),
+                    // what ipykernel takes for Ctrl-D
+                    Answer::Gone => Some("\u{4}".into()),
+                };
+                if let Some(value) = value {
+                    let reply = self.stdin.header("input_reply");
+                    self.stdin.send(&reply, Some(parent), &json!({ "value": value }))?;
+                    asking = None;
+                }
+            }
+            if ready[0] {
+                match self.shell.recv(Duration::ZERO)? {
+                    Some(reply) if reply.parent_id.as_ref() == Some(&request.msg_id) => break reply,
+                    _ => {}
+                }
+            }
+        };
 
-        if let Ok(Response::Shell(ShellResponse::Execute { content, .. })) = response {
-            Ok(content.execution_count)
-        } else {
-            Err("failed to run code".into())
+        let status = reply.content["status"].as_str().unwrap_or_default();
+        match reply.content["execution_count"].as_i64() {
+            Some(execution_count) => Ok(ExecuteReply {
+                execution_count,
+                failed: status != "ok",
+            }),
+            None => Err(format!("failed to run code ({})", status).into()),
         }
     }
 }
@@ -67,55 +483,245 @@ impl KernelConnection {
 pub struct Kernel {
     pub spec: KernelSpec,
     process: process::Child,
-    pub file: String,
+    /// connection file in `runtime_dir`
+    pub file: PathBuf,
+    /// what is in `file`, to open channels of our own with
+    info: ConnectionInfo,
 }
 
 impl Drop for Kernel {
     fn drop(&mut self) {
-        // kill child once the Kernel object goes out of scope
-        self.shutdown().expect("Failed to shutdown kernel");
+        // the graceful way is `shutdown`, which needs to await. all that is left here is making
+        // sure the child does not outlive us, and SIGKILL takes effect right away
+        if let Ok(None) = self.process.try_wait() {
+            warn!("kernel {} was not shut down, killing it", self.spec.name);
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+        let _ = fs::remove_file(&self.file);
     }
 }
 
 impl Kernel {
-    pub fn shutdown(&mut self) -> Result<()> {
-        info!("Shutting down kernel...");
-        self.process.kill()?; // FIXME obviously not a good way either
-        fs::remove_file(&self.file)?;
-        Ok(())
+    /// Stops the kernel and removes its connection file. Safe to call more than once.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        info!("shutting down kernel {}", self.spec.name);
+        let stopped = self.stop(false).await;
+        match fs::remove_file(&self.file) {
+            // already removed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        stopped
     }
 
-    pub async fn start(spec: &KernelSpec) -> Result<Self> {
-        let file = format!("./kernel-{}.json", Uuid::new_v4());
-        let mut args = spec.args.to_vec();
-        args.push(file.clone());
-        let args = args;
-
-        let process = process::Command::new(&spec.cmd)
-            .args(&args)
-            .spawn()?;
+    /// Writes a connection file with free ports and launches the kernel on it.
+    ///
+    /// The kernel is not listening yet when this returns, `connect` waits for it.
+    pub fn start(spec: &KernelSpec) -> Result<Self> {
+        let file = runtime_dir()?.join(format!("kernel-{}.json", Uuid::new_v4()));
+        let info = ConnectionInfo::new(&spec.name)?;
+        info.write(&file)?;
+        let process = match spawn(spec, &file) {
+            Ok(process) => process,
+            Err(e) => {
+                let _ = fs::remove_file(&file);
+                return Err(e.into());
+            }
+        };
 
-        let kernel = Kernel {
+        info!("kernel started");
+        Ok(Kernel {
             spec: spec.clone(),
             process,
             file,
-        };
+            info,
+        })
+    }
 
-        // FIXME obviously not a good solution
-        std::thread::sleep(std::time::Duration::from_millis(5000));
-        info!("kernel started");
-        Ok(kernel)
+    /// Connects to the kernel and waits until it answers a `kernel_info_request`.
+    pub async fn connect(&mut self) -> Result<KernelConnection> {
+        self.wait_ready().await?;
+
+        // the kernel sends input_requests to the stdin socket with the identity of the shell
+        // socket the execute_request came from
+        let session = Uuid::new_v4().to_string();
+        Ok(KernelConnection {
+            client: self.client()?,
+            shell: self.info.channel(self.info.shell_port, &session)?,
+            stdin: self.info.channel(self.info.stdin_port, &session)?,
+            sent: Default::default(),
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.process.id()
+    }
+
+    /// Whether the kernel process is gone, e.g. because it crashed or was OOM-killed.
+    pub fn has_exited(&mut self) -> bool {
+        matches!(self.process.try_wait(), Ok(Some(_)))
+    }
+
+    /// Interrupts whatever the kernel is running, like Ctrl-C in a terminal.
+    ///
+    /// Sends SIGINT, or an `interrupt_request` if the kernelspec asks for that.
+    pub async fn interrupt(&self) -> Result<()> {
+        info!("interrupting kernel {}", self.spec.name);
+        match self.spec.interrupt_mode {
+            InterruptMode::Signal => Ok(signal(&self.process, libc::SIGINT)?),
+            InterruptMode::Message => {
+                let reply = self.control("interrupt_request", json!({}), INTERRUPT_TIMEOUT).await?;
+                match reply.content["status"].as_str() {
+                    Some("ok") => Ok(()),
+                    _ => Err(format!("kernel {} refused interrupt_request", self.spec.name).into()),
+                }
+            }
+        }
+    }
+
+    /// Asks the kernel to shut down for a restart, then starts it again on the same connection file.
+    ///
+    /// Reusing the ports means existing `KernelConnection`s keep working once the kernel is back.
+    pub async fn restart(&mut self) -> Result<()> {
+        info!("restarting kernel {}", self.spec.name);
+        self.stop(true).await?;
+
+        self.process = spawn(&self.spec, &self.file)?;
+        self.wait_ready().await?;
+        info!("kernel {} restarted", self.spec.name);
+        Ok(())
+    }
+
+    /// Sends `shutdown_request`, escalating to SIGTERM and then SIGKILL if the kernel does not
+    /// exit within `SHUTDOWN_GRACE`, and reaps the process.
+    async fn stop(&mut self, restart: bool) -> Result<()> {
+        if self.process.try_wait()?.is_some() {
+            return Ok(());
+        }
+
+        let request = self.control("shutdown_request", json!({ "restart": restart }), SHUTDOWN_GRACE);
+        if let Err(e) = request.await {
+            warn!("kernel {} did not answer shutdown_request: {}", self.spec.name, e);
+        }
+        if self.wait_exit(SHUTDOWN_GRACE).await? {
+            return Ok(());
+        }
+
+        warn!("kernel {} did not shut down in time, sending SIGTERM", self.spec.name);
+        signal(&self.process, libc::SIGTERM)?;
+        if self.wait_exit(SHUTDOWN_GRACE).await? {
+            return Ok(());
+        }
+
+        warn!("kernel {} ignored SIGTERM, killing it", self.spec.name);
+        self.process.kill()?;
+        // SIGKILL cannot be ignored, so this returns right away
+        self.process.wait()?;
+        Ok(())
     }
 
-    pub async fn connect(&self) -> Result<KernelConnection> {
-        let file = std::fs::File::open(&self.file).unwrap();
+    /// Whether the kernel exited within `timeout`, reaping it if so.
+    async fn wait_exit(&mut self, timeout: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.process.try_wait()?.is_some() {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// A new client for the kernel, independent of the ones handed out before.
+    pub fn client(&self) -> Result<Client> {
+        let file = std::fs::File::open(&self.file)?;
         //let client = Client::existing().unwrap(); // doesn't work
-        let client = Client::from_reader(file).unwrap();
-        info!("connected to kernel");
+        Ok(Client::from_reader(file).map_err(|e| e.to_string())?)
+    }
 
-        Ok(KernelConnection { 
-            client,
-            last_run_cell: Arc::new(sync::RwLock::new(None)),
-        })
+    /// A shell channel of its own, so its replies do not get mixed up with anyone else's.
+    pub fn shell(&self) -> Result<wire::Channel> {
+        Ok(self.info.channel(self.info.shell_port, &Uuid::new_v4().to_string())?)
     }
+
+    /// Sends a control request on a channel of its own and waits up to `timeout` for the reply.
+    ///
+    /// Control requests are answered even while a cell runs on the shell channel.
+    async fn control(&self, msg_type: &'static str, content: Value, timeout: Duration) -> Result<wire::Message> {
+        let control = self.info.channel(self.info.control_port, &Uuid::new_v4().to_string())?;
+        let reply = tokio::task::spawn_blocking(move || control.request(msg_type, &content, timeout)).await?;
+        Ok(reply?)
+    }
+
+    /// Waits until the kernel answers a `kernel_info_request`.
+    async fn wait_ready(&mut self) -> Result<()> {
+        let mut shell = self.shell()?;
+        // the request sits in the zmq queue until the kernel listens, so a reply means it is ready
+        let request = shell.header("kernel_info_request");
+        shell.send(&request, None, &json!({}))?;
+
+        let deadline = Instant::now() + startup_timeout();
+        loop {
+            // wait in short blocking steps, so giving up never leaves a thread behind
+            let (returned, reply) = tokio::task::spawn_blocking(move || {
+                let reply = shell.recv(POLL_INTERVAL);
+                (shell, reply)
+            })
+            .await?;
+            shell = returned;
+
+            if let Some(reply) = reply? {
+                if reply.parent_id.as_ref() == Some(&request.msg_id) {
+                    let content = &reply.content;
+                    info!(
+                        "connected to kernel: {} {}",
+                        content["implementation"].as_str().unwrap_or_default(),
+                        content["implementation_version"].as_str().unwrap_or_default()
+                    );
+                    return Ok(());
+                }
+            }
+            self.check_running()?;
+            if Instant::now() >= deadline {
+                return Err(format!("kernel {} did not answer kernel_info_request in time", self.spec.name).into());
+            }
+        }
+    }
+
+    /// Fails if the kernel process already exited, e.g. because of a broken kernelspec.
+    fn check_running(&mut self) -> Result<()> {
+        match self.process.try_wait()? {
+            Some(status) => Err(format!("kernel {} exited during startup ({})", self.spec.name, status).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn spawn(spec: &KernelSpec, connection_file: &Path) -> io::Result<process::Child> {
+    let argv = spec.command(&connection_file.to_string_lossy());
+    info!("starting kernel {}: {:?}", spec.name, argv);
+    process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .envs(&spec.env)
+        .spawn()
+}
+
+fn signal(process: &process::Child, signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: kill has no memory safety requirements, and the pid is our own unreaped child
+    if unsafe { libc::kill(process.id() as libc::pid_t, signal) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// How long a kernel gets to answer its first request, from `KERNEL_STARTUP_TIMEOUT`.
+fn startup_timeout() -> Duration {
+    let secs = env::var("KERNEL_STARTUP_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_STARTUP_TIMEOUT);
+    Duration::from_secs(secs)
 }
@@ -48,6 +48,14 @@ async fn main() {
     }
     pretty_env_logger::init();
 
+    // fail fast on a bad ROOT rather than on the first request
+    let root = sandbox::get().root();
+    info!("serving notebooks from {:?}", root);
+
+    // connection files of kernels that died with a previous run
+    kernel::sweep_connection_files();
+    tokio::spawn(session::cull());
+
     let port = env::var_os("PORT")
         .map(|s| s.into_string().unwrap().parse().unwrap())
         .unwrap_or(8080);
@@ -57,7 +65,8 @@ async fn main() {
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["Content-Type"])
+        .allow_headers(vec!["Content-Type", "If-Match"])
+        .expose_headers(vec!["ETag"])
         .allow_methods(vec!["GET", "POST"]);
     let api = filters::api(notify_shutdown_rx, shutdown_complete_tx)
         .with(cors);
@@ -68,22 +77,40 @@ async fn main() {
 
     let (_addr, server) = warp::serve(routes)
         .bind_with_graceful_shutdown(([0, 0, 0, 0], port), async move {
-            loop {
-                tokio::signal::ctrl_c()
-                    .await
-                    .expect("failed to listen to shutdown signal");
-                info!("ctrl c received, shutting down gracefully");
-                let _ = notify_shutdown_tx.send(());
-                let _ = shutdown_complete_rx.recv().await;
-                break;
-            }
+            shutdown_signal().await;
+            info!("shutting down gracefully");
+            let _ = notify_shutdown_tx.send(());
+            // every websocket holds a sender until it has closed
+            let _ = shutdown_complete_rx.recv().await;
+            session::shutdown_all().await;
+            handlers::flush_writes().await;
+            info!("all kernels stopped");
         });
 
     info!("listening on {}", _addr); 
     server.await;
 }
 
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to listen to shutdown signal");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("failed to listen to shutdown signal"),
+        _ = terminate.recv() => {}
+    }
+}
+
+mod document;
+mod error;
 mod filters;
+mod fsutil;
 mod models;
 mod handlers;
 mod kernel;
+mod migrate;
+mod sandbox;
+mod session;
+mod validate;
+mod watch;
+mod wire;
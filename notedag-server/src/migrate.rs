@@ -0,0 +1,158 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+/// Version of the `.ind` layout this server reads and writes.
+///
+/// - 0: unversioned files, with UI state (`focusedGroup`, `focusedCell`, `activeGroupChain`)
+///   at the top level
+/// - 1: UI state moved under `ui`, `activeGroupChain` dropped since it is derived from `nextChild`
+pub const CURRENT_VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` upgrades a document from version `n` to `n + 1`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); CURRENT_VERSION as usize] = [v0_to_v1];
+
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    NotAnObject,
+    /// `version` is there, but not a non-negative integer
+    BadVersion { version: Value },
+    TooNew { version: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "{}", e),
+            Error::NotAnObject => write!(f, "expected a JSON object"),
+            Error::BadVersion { version } => {
+                write!(f, "notebook format version {} is not a version number", version)
+            }
+            Error::TooNew { version } => write!(
+                f,
+                "notebook format version {} is newer than the supported version {}",
+                version, CURRENT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Parses `contents` and brings it up to `CURRENT_VERSION`.
+pub fn upgrade(contents: &str) -> Result<Value, Error> {
+    let mut value: Value = serde_json::from_str(contents)?;
+    let doc = value.as_object_mut().ok_or(Error::NotAnObject)?;
+
+    // only files from before versioning have none
+    let version = match doc.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or_else(|| Error::BadVersion { version: version.clone() })?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(Error::TooNew { version });
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(doc);
+    }
+    if version != CURRENT_VERSION {
+        debug!("migrated notebook from version {} to {}", version, CURRENT_VERSION);
+    }
+    doc.insert("version".into(), CURRENT_VERSION.into());
+
+    Ok(value)
+}
+
+fn v0_to_v1(doc: &mut Map<String, Value>) {
+    let mut ui = Map::new();
+    for key in ["focusedGroup", "focusedCell"] {
+        if let Some(value) = doc.remove(key) {
+            ui.insert(key.into(), value);
+        }
+    }
+    doc.remove("activeGroupChain");
+    doc.insert("ui".into(), Value::Object(ui));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn v0_to_v1_moves_ui_state() {
+        let mut doc = json!({
+            "cells": {},
+            "groups": {},
+            "root": "r",
+            "focusedGroup": "g",
+            "focusedCell": "c",
+            "activeGroupChain": [{ "id": "r" }],
+        });
+        v0_to_v1(doc.as_object_mut().unwrap());
+        assert_eq!(
+            doc,
+            json!({ "cells": {}, "groups": {}, "root": "r", "ui": { "focusedGroup": "g", "focusedCell": "c" } })
+        );
+    }
+
+    #[test]
+    fn v0_to_v1_without_ui_state() {
+        let mut doc = json!({ "cells": {}, "groups": {}, "root": "r" });
+        v0_to_v1(doc.as_object_mut().unwrap());
+        assert_eq!(doc, json!({ "cells": {}, "groups": {}, "root": "r", "ui": {} }));
+    }
+
+    #[test]
+    fn upgrade_unversioned() {
+        let doc = upgrade(r#"{"root": "r", "focusedGroup": "g", "activeGroupChain": []}"#).unwrap();
+        assert_eq!(doc, json!({ "root": "r", "ui": { "focusedGroup": "g" }, "version": CURRENT_VERSION }));
+    }
+
+    #[test]
+    fn upgrade_current() {
+        // already migrated, so a `focusedGroup` at the top level is left alone
+        let contents = json!({ "version": CURRENT_VERSION, "focusedGroup": "g", "ui": {} });
+        assert_eq!(upgrade(&contents.to_string()).unwrap(), contents);
+    }
+
+    #[test]
+    fn upgrade_too_new() {
+        let contents = json!({ "version": CURRENT_VERSION + 1 }).to_string();
+        assert!(matches!(upgrade(&contents), Err(Error::TooNew { version }) if version == CURRENT_VERSION + 1));
+    }
+
+    #[test]
+    fn upgrade_bad_version() {
+        for version in [json!("2"), json!(1.5), json!(-1), json!(null)] {
+            let contents = json!({ "version": version, "focusedGroup": "g" }).to_string();
+            assert!(
+                matches!(upgrade(&contents), Err(Error::BadVersion { version: ref bad }) if *bad == version),
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn upgrade_not_a_notebook() {
+        assert!(matches!(upgrade("[]"), Err(Error::NotAnObject)));
+        assert!(matches!(upgrade("{"), Err(Error::Json(_))));
+    }
+
+    #[test]
+    fn example_upgrades() {
+        let doc = upgrade(include_str!("../../tests/refactor_example.ind")).unwrap();
+        assert_eq!(doc["version"], CURRENT_VERSION);
+        assert!(doc.get("activeGroupChain").is_none());
+        assert_eq!(doc["ui"]["focusedGroup"], "87b8edf6-f7d4-4364-8bfe-57abed994170");
+    }
+}
@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use warp::ws::Message;
 
+use crate::document::KernelspecRef;
+use crate::validate::Issue;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NoteDAG {
@@ -12,6 +17,14 @@ pub struct NoteDAG {
 pub struct NoteDAGWrite {
     pub file_path: String,
     pub contents: String,
+    /// revision the client started editing from, same as sending `If-Match`
+    pub base_revision: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteDAGWritten {
+    pub revision: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -31,31 +44,274 @@ pub struct ListOptions {
     pub file_path: Option<String>,
 }
 
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum WatchEventKind {
+    Create,
+    Modify,
+    Delete,
+    Rename,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchEvent {
+    pub kind: WatchEventKind,
+    /// state after the change, only the name and path are meaningful for deletes
+    pub item: ListItem,
+    /// previous path of a renamed entry
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameOptions {
+    pub file_path: String,
+    /// file name only, the entry stays in its directory
+    pub new_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveOptions {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteOptions {
+    pub file_path: String,
+    /// skip the trash
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteResult {
+    /// where the entry can be restored from, `None` if it was deleted permanently
+    pub trash_path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorMessage {
+    /// e.g. `not_found`, see `Error::code`
+    pub code: String,
+    pub message: String,
+    /// error specific, e.g. the current revision on a conflict
+    pub details: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidateOptions {
+    pub file_path: String,
+    /// refused, repairing writes a file and is done by `POST /notedag/repair`
+    #[serde(default)]
+    pub repair: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+    pub repaired_path: Option<String>,
+}
+
+/// A live kernel, as listed by `/kernel/running`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunningKernel {
+    /// session id, which the other `/kernel` endpoints take
+    pub id: String,
+    pub notebook: Option<String>,
+    pub kernelspec: KernelspecRef,
+    /// missing while the kernel restarts
+    pub pid: Option<u32>,
+    /// unix time in ms, like `last_activity`
+    pub started: u128,
+    pub execution_state: String,
+    pub last_activity: u128,
+    /// attached websockets
+    pub clients: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KernelId {
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompleteRequest {
+    /// session id, see `RunningKernel`
+    pub id: String,
+    pub code: String,
+    /// in unicode code points, like everywhere in the Jupyter protocol
+    pub cursor_pos: u64,
+}
+
+/// What `complete_request` came up with. `cursor_start..cursor_end` is the text to replace.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Completion {
+    pub matches: Vec<String>,
+    pub cursor_start: u64,
+    pub cursor_end: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InspectRequest {
+    /// session id, see `RunningKernel`
+    pub id: String,
+    pub code: String,
+    /// in unicode code points, like everywhere in the Jupyter protocol
+    pub cursor_pos: u64,
+    /// also show the source, like `??` in IPython
+    #[serde(default)]
+    pub detail: bool,
+}
+
+/// Documentation for the name at the cursor, as `inspect_request` returns it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Inspection {
+    pub found: bool,
+    /// by mime type, usually `text/plain` with ANSI colors
+    pub data: HashMap<String, String>,
+}
+
+/// Messages clients send over the kernel websocket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum KernelRequest {
+    RunCell(RunCell),
+    /// stop the running cell
+    Interrupt,
+    /// start over with a fresh interpreter
+    Restart,
+    /// ask for the cells waiting to run, answered with a "queue" update
+    ListQueue,
+    /// drop a cell that has not started yet, a running one has to be interrupted
+    CancelCell { id: String },
+    /// drop every cell that has not started yet
+    ClearQueue,
+    /// move a cell that has not started yet to `position` in the queue, counting from 1
+    MoveCell { id: String, position: usize },
+    /// answer the `input()` that cell `id` asked for with an "input" or "password" update
+    InputReply { id: String, value: String },
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RunCell {
     pub id: String,
     pub value: String,
+    /// drop the rest of the queue if this cell fails, like Jupyter's `stop_on_error`
+    #[serde(default)]
+    pub stop_on_error: bool,
+    /// who asked for the run, filled in by the server
+    #[serde(skip)]
+    pub user: usize,
+}
+
+/// A cell waiting in the execution queue of a session.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedCell {
+    pub id: String,
+    pub user: usize,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RunCellUpdate {
     pub id: String,
+    /// user that ran the cell, other clients of the session see the update too
+    pub user: usize,
     pub name: String,
     pub value: String,
 }
 
-impl Into<Message> for RunCellUpdate {
-    fn into(self) -> Message {
-        let new_msg = serde_json::to_string(&self).unwrap();
+impl From<RunCellUpdate> for Message {
+    fn from(update: RunCellUpdate) -> Self {
+        let new_msg = serde_json::to_string(&update).unwrap();
         Message::text(new_msg)
     }
 }
 
 
+/// Kernel output that belongs to no cell we ran, e.g. from a thread a finished cell started.
+/// `name` and `value` are like in `RunCellUpdate`.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
+pub struct NotebookOutput {
+    /// always "notebook", to tell it apart from the other updates
+    pub channel: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl NotebookOutput {
+    pub fn new(name: impl Into<String>, value: String) -> Self {
+        NotebookOutput {
+            channel: "notebook".into(),
+            name: name.into(),
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct KernelUpdate {
     pub status: String,
+    /// what went wrong, when `status` is "error"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// the kernel this socket is connected to, sent once it is ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernelspec: Option<KernelspecRef>,
+    /// id to reattach to the same kernel with, sent once it is ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// id of the receiving client, to tell its own runs from those of others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<usize>,
+    /// cells waiting to run, in order, when `status` is "queue"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<Vec<QueuedCell>>,
+}
+
+impl KernelUpdate {
+    pub fn error(message: impl Into<String>) -> Self {
+        KernelUpdate {
+            status: "error".into(),
+            message: Some(message.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<KernelUpdate> for Message {
+    fn from(update: KernelUpdate) -> Self {
+        Message::text(serde_json::to_string(&update).unwrap())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KernelOptions {
+    /// kernelspec name, see `/kernel/list`. Defaults to `python3`, or the first one found
+    pub kernel: Option<String>,
+    /// notebook to share a kernel with across reconnects
+    pub file_path: Option<String>,
+    /// session to reattach to, takes precedence over `file_path`
+    pub session: Option<String>,
+    /// `seq` of the last update received from `session`, to get the ones after it again
+    pub since: Option<u64>,
 }
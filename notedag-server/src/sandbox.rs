@@ -0,0 +1,218 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Confines client supplied paths to the `ROOT` directory.
+#[derive(Debug)]
+pub struct Sandbox {
+    /// canonical path of `ROOT`
+    root: PathBuf,
+    /// allow symlinks inside the root that point outside of it
+    follow_symlinks: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// absolute paths, or `..` climbing above the root
+    OutsideRoot(String),
+    /// a symlink on the way resolves outside the root
+    SymlinkOutsideRoot(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutsideRoot(path) => write!(f, "{} is outside the root directory", path),
+            Error::SymlinkOutsideRoot(path) => write!(f, "{} links outside the root directory", path),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+static SANDBOX: OnceLock<Sandbox> = OnceLock::new();
+
+/// The sandbox configured through `ROOT` and `FOLLOW_SYMLINKS`.
+pub fn get() -> &'static Sandbox {
+    SANDBOX.get_or_init(|| Sandbox::from_env().expect("ROOT must be an existing directory"))
+}
+
+impl Sandbox {
+    pub fn from_env() -> io::Result<Self> {
+        let root = env::var_os("ROOT")
+            .filter(|root| !root.is_empty())
+            .unwrap_or_else(|| ".".into());
+        let follow_symlinks = env::var("FOLLOW_SYMLINKS")
+            .map(|s| s == "1" || s == "true")
+            .unwrap_or(false);
+        Ok(Sandbox {
+            root: fs::canonicalize(root)?,
+            follow_symlinks,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps a path relative to the root onto the filesystem, refusing anything that escapes it.
+    ///
+    /// The path does not need to exist, which lets callers create files.
+    pub fn resolve(&self, file_path: &str) -> Result<PathBuf, Error> {
+        let mut relative = PathBuf::new();
+        for component in Path::new(file_path).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(Error::OutsideRoot(file_path.into()));
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(Error::OutsideRoot(file_path.into()));
+                }
+            }
+        }
+        let path = self.root.join(relative);
+
+        if !self.follow_symlinks {
+            // the longest existing prefix tells us where symlinks on the way really lead
+            let existing = path.ancestors().find(|p| p.symlink_metadata().is_ok()).unwrap_or(&self.root);
+            let canonical = match fs::canonicalize(existing) {
+                Ok(canonical) => canonical,
+                // a dangling symlink, we cannot tell where it goes
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(Error::SymlinkOutsideRoot(file_path.into()));
+                }
+                Err(e) => return Err(Error::Io(e)),
+            };
+            if !canonical.starts_with(&self.root) {
+                return Err(Error::SymlinkOutsideRoot(file_path.into()));
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Inverse of `resolve`, for paths we hand back to clients.
+    pub fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A scratch directory with `root/` to sandbox and `outside/` next to it, removed on drop.
+    ///
+    /// ```text
+    /// root/dir/file.ind
+    /// root/link_in -> root/dir
+    /// root/link_out -> outside
+    /// root/dangling -> missing
+    /// outside/secret
+    /// ```
+    struct Fixture {
+        base: PathBuf,
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let base = env::temp_dir().join(format!("notedag-sandbox-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(base.join("root/dir")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            let base = fs::canonicalize(base).unwrap();
+            let root = base.join("root");
+            fs::write(root.join("dir/file.ind"), "{}").unwrap();
+            fs::write(base.join("outside/secret"), "").unwrap();
+            symlink(root.join("dir"), root.join("link_in")).unwrap();
+            symlink(base.join("outside"), root.join("link_out")).unwrap();
+            symlink(base.join("missing"), root.join("dangling")).unwrap();
+            Fixture { base, root }
+        }
+
+        fn sandbox(&self, follow_symlinks: bool) -> Sandbox {
+            Sandbox { root: self.root.clone(), follow_symlinks }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn relative_paths() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(false);
+        let file = fixture.root.join("dir/file.ind");
+        assert_eq!(sandbox.resolve("dir/file.ind").unwrap(), file);
+        assert_eq!(sandbox.resolve("./dir/../dir/file.ind").unwrap(), file);
+        assert_eq!(sandbox.resolve("").unwrap(), fixture.root);
+        assert_eq!(sandbox.resolve("dir/..").unwrap(), fixture.root);
+        assert_eq!(sandbox.relative(&file), "dir/file.ind");
+    }
+
+    #[test]
+    fn parent_dirs_cannot_escape() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(true);
+        for path in ["..", "../outside/secret", "dir/../../outside", "dir/../../root/dir/file.ind"] {
+            assert!(matches!(sandbox.resolve(path), Err(Error::OutsideRoot(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn absolute_paths() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(true);
+        let inside = fixture.root.join("dir/file.ind");
+        for path in ["/etc/passwd", "/", inside.to_str().unwrap()] {
+            assert!(matches!(sandbox.resolve(path), Err(Error::OutsideRoot(_))), "{}", path);
+        }
+    }
+
+    #[test]
+    fn symlinks_out_of_root() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(false);
+        for path in ["link_out", "link_out/secret", "link_out/new.ind", "dangling", "dangling/new.ind"] {
+            assert!(matches!(sandbox.resolve(path), Err(Error::SymlinkOutsideRoot(_))), "{}", path);
+        }
+        // links that stay inside the root are fine either way
+        assert_eq!(sandbox.resolve("link_in/file.ind").unwrap(), fixture.root.join("link_in/file.ind"));
+    }
+
+    #[test]
+    fn symlinks_out_of_root_when_following() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(true);
+        for path in ["link_out/secret", "link_out/new.ind", "dangling"] {
+            assert_eq!(sandbox.resolve(path).unwrap(), fixture.root.join(path));
+        }
+        // following symlinks does not let `..` out
+        assert!(matches!(sandbox.resolve("link_out/../.."), Err(Error::OutsideRoot(_))));
+    }
+
+    #[test]
+    fn targets_that_do_not_exist_yet() {
+        let fixture = Fixture::new();
+        let sandbox = fixture.sandbox(false);
+        assert_eq!(sandbox.resolve("new.ind").unwrap(), fixture.root.join("new.ind"));
+        assert_eq!(sandbox.resolve("new/deep/file.ind").unwrap(), fixture.root.join("new/deep/file.ind"));
+        assert_eq!(sandbox.resolve("dir/new/file.ind").unwrap(), fixture.root.join("dir/new/file.ind"));
+    }
+}
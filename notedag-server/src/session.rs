@@ -0,0 +1,998 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{self, Arc, OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use jupyter_client::responses::{ExecutionState, IoPubResponse, Response};
+use tokio::sync::{mpsc, Mutex, Notify, OnceCell};
+use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use warp::ws::Message;
+
+use crate::kernel::{Answer, Input, Kernel, KernelConnection, KernelSpec, SentCells};
+use crate::models::{
+    Completion, Inspection, KernelOptions, KernelUpdate, NotebookOutput, QueuedCell, RunCell,
+    RunCellUpdate, RunningKernel,
+};
+use crate::wire;
+
+/// Updates kept for clients that lost their connection, the oldest are dropped first.
+const HISTORY_LIMIT: usize = 1000;
+
+/// How long a restart waits for an interrupted cell to finish.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minutes a kernel without clients is kept around, see `Limits`.
+const DEFAULT_DETACHED_TIMEOUT: u64 = 60;
+
+/// How long an `input()` waits for the user to answer before it gets an EOFError.
+const INPUT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long completion and inspection wait for the kernel.
+const ASSIST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the culler checks on kernels.
+const CULL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Live sessions by id, including those whose kernel is still starting.
+///
+/// The lock is only held to look up or reserve an entry, kernels start and stop outside of it.
+/// Two tabs opening the same notebook at once wait for the same `Slot`, and end up with one kernel.
+static SESSIONS: sync::Mutex<BTreeMap<String, Arc<Slot>>> = sync::Mutex::new(BTreeMap::new());
+
+/// An entry in `SESSIONS`, filled in once the kernel is up.
+struct Slot {
+    notebook: Option<String>,
+    /// kernelspec name, `None` for the default one
+    kernel: Option<String>,
+    session: OnceCell<Arc<Session>>,
+}
+
+/// A running kernel and the clients looking at it.
+///
+/// Sessions outlive websocket connections: a client that reconnects after a refresh or a
+/// network hiccup gets its kernel back, along with the updates it missed. Every attached client
+/// sees every update, whoever ran the cell.
+///
+/// Updates are numbered by `seq`, so a client can tell which ones it missed, see `attach`.
+pub struct Session {
+    pub id: String,
+    /// notebook the kernel was started for
+    pub notebook: Option<String>,
+    pub spec: KernelSpec,
+    pub kernel: Mutex<Kernel>,
+    pub conn: Mutex<KernelConnection>,
+    pub started: SystemTime,
+    output: sync::Mutex<Output>,
+    activity: sync::Mutex<Activity>,
+    /// cells waiting for the kernel, run one at a time in order by `run_queue`
+    queue: sync::Mutex<VecDeque<RunCell>>,
+    /// wakes `run_queue` when a cell is queued, or the session is gone
+    queued: Arc<Notify>,
+    /// shell client for completion and inspection, which do not wait for `conn`
+    assist: Mutex<Option<wire::Channel>>,
+    /// what the running cell is waiting for the user to type, see `Input`
+    input: sync::Mutex<Option<PendingInput>>,
+    /// set while the kernel is being stopped, so the running cell stops waiting for its reply
+    stopping: AtomicBool,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // lets `run_queue` notice that it can stop
+        self.queued.notify_one();
+    }
+}
+
+/// An `input()` in the running cell, asked of the user that ran the cell.
+struct PendingInput {
+    cell: String,
+    user: usize,
+    asked: Instant,
+    /// what the user typed, once they did
+    line: Option<String>,
+}
+
+struct Activity {
+    /// last `execution_state` the kernel reported
+    execution_state: String,
+    /// last time a client asked for something or the kernel had something to say
+    last_activity: SystemTime,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            execution_state: "starting".into(),
+            last_activity: SystemTime::now(),
+        }
+    }
+}
+
+struct Output {
+    /// attached websockets, by user id
+    clients: BTreeMap<usize, mpsc::UnboundedSender<Message>>,
+    /// the last `HISTORY_LIMIT` updates and their `seq`, oldest first
+    history: VecDeque<(u64, Message)>,
+    /// `seq` of the next update
+    next_seq: u64,
+    /// `seq` of the first update sent while no client was attached
+    unseen: Option<u64>,
+    /// when the last client left, for culling
+    detached_since: Option<SystemTime>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            clients: BTreeMap::new(),
+            history: VecDeque::new(),
+            next_seq: 1,
+            unseen: None,
+            // nobody is attached until the client that started the session is
+            detached_since: Some(SystemTime::now()),
+        }
+    }
+}
+
+/// Content of a `complete_reply`.
+#[derive(Deserialize)]
+struct CompleteReply {
+    matches: Vec<String>,
+    cursor_start: u64,
+    cursor_end: u64,
+}
+
+/// Content of an `inspect_reply`.
+#[derive(Deserialize)]
+struct InspectReply {
+    found: bool,
+    /// by mime type, text formats are strings but `application/json` and the like are not
+    #[serde(default)]
+    data: HashMap<String, Value>,
+}
+
+/// When kernels get shut down on their own, and how many may run at once.
+struct Limits {
+    /// `KERNEL_IDLE_TIMEOUT`, minutes without running anything
+    idle_timeout: Option<Duration>,
+    /// `KERNEL_DETACHED_TIMEOUT`, minutes without any client attached
+    detached_timeout: Option<Duration>,
+    /// `MAX_KERNELS`
+    max_kernels: Option<usize>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+fn limits() -> &'static Limits {
+    LIMITS.get_or_init(|| {
+        // 0 turns a limit off
+        let var = |name: &str, default: u64| {
+            env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        };
+        let minutes = |minutes: u64| (minutes > 0).then(|| Duration::from_secs(minutes * 60));
+        Limits {
+            idle_timeout: minutes(var("KERNEL_IDLE_TIMEOUT", 0)),
+            detached_timeout: minutes(var("KERNEL_DETACHED_TIMEOUT", DEFAULT_DETACHED_TIMEOUT)),
+            max_kernels: Some(var("MAX_KERNELS", 0) as usize).filter(|max| *max > 0),
+        }
+    })
+}
+
+/// Finds the session a client asks for, by id or by notebook, or starts a new one.
+///
+/// A notebook that asks for a different kernel than the one running gets its old session
+/// shut down.
+pub async fn open(options: &KernelOptions) -> Result<Arc<Session>, String> {
+    loop {
+        let (id, slot) = reserve(options)?;
+        let started = slot
+            .session
+            .get_or_try_init(|| Session::start(&id, slot.kernel.as_deref(), slot.notebook.clone()))
+            .await;
+        let session = match started {
+            Ok(session) => Arc::clone(session),
+            Err(message) => {
+                remove(&id, &slot);
+                return Err(message);
+            }
+        };
+
+        if !is_reserved(&id, &slot) {
+            // `shutdown` got to it while the kernel was starting
+            session.shutdown("the kernel was shut down").await;
+            return Err("the kernel was shut down while it was starting".into());
+        }
+        if options.kernel.as_ref().is_none_or(|kernel| *kernel == session.spec.name) {
+            return Ok(session);
+        }
+        if remove(&id, &slot) {
+            session.shutdown("the notebook switched kernels").await;
+        }
+    }
+}
+
+/// The slot a client asks for, by session id or by notebook, or a new one.
+fn reserve(options: &KernelOptions) -> Result<(String, Arc<Slot>), String> {
+    let mut sessions = SESSIONS.lock().unwrap();
+
+    let existing = match (&options.session, &options.file_path) {
+        (Some(id), _) => sessions.get_key_value(id),
+        (None, Some(notebook)) => sessions
+            .iter()
+            .find(|(_, slot)| slot.notebook.as_ref() == Some(notebook)),
+        (None, None) => None,
+    };
+    if let Some((id, slot)) = existing {
+        info!("reattaching to session {}", id);
+        return Ok((id.clone(), Arc::clone(slot)));
+    }
+
+    if let Some(max_kernels) = limits().max_kernels {
+        if sessions.len() >= max_kernels {
+            return Err(format!(
+                "{} kernels are running, which is the limit. shut one down on the kernels page first",
+                sessions.len()
+            ));
+        }
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let slot = Arc::new(Slot {
+        notebook: options.file_path.clone(),
+        kernel: options.kernel.clone(),
+        session: OnceCell::new(),
+    });
+    sessions.insert(id.clone(), Arc::clone(&slot));
+    Ok((id, slot))
+}
+
+fn is_reserved(id: &str, slot: &Arc<Slot>) -> bool {
+    SESSIONS.lock().unwrap().get(id).is_some_and(|current| Arc::ptr_eq(current, slot))
+}
+
+/// Takes `slot` out of `SESSIONS`, unless someone else did first.
+fn remove(id: &str, slot: &Arc<Slot>) -> bool {
+    let mut sessions = SESSIONS.lock().unwrap();
+    if !sessions.get(id).is_some_and(|current| Arc::ptr_eq(current, slot)) {
+        return false;
+    }
+    sessions.remove(id);
+    true
+}
+
+/// All sessions whose kernel is up.
+pub fn list() -> Vec<Arc<Session>> {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|slot| slot.session.get().cloned())
+        .collect()
+}
+
+pub fn get(id: &str) -> Option<Arc<Session>> {
+    SESSIONS.lock().unwrap().get(id).and_then(|slot| slot.session.get().cloned())
+}
+
+/// Stops the kernel of session `id` for good. Returns `false` if there is no such session.
+///
+/// A kernel that is still starting is stopped by `open` once it is up.
+pub async fn shutdown(id: &str, reason: &str) -> bool {
+    let slot = SESSIONS.lock().unwrap().remove(id);
+    match slot {
+        Some(slot) => {
+            if let Some(session) = slot.session.get() {
+                session.shutdown(reason).await;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Shuts down kernels that have been idle or without clients for longer than `Limits` allow.
+/// Runs until the server exits.
+pub async fn cull() {
+    let limits = limits();
+    if limits.idle_timeout.is_none() && limits.detached_timeout.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(CULL_INTERVAL);
+    loop {
+        interval.tick().await;
+        for session in list() {
+            if let Some(reason) = session.cull_reason(limits) {
+                shutdown(&session.id, &reason).await;
+            }
+        }
+    }
+}
+
+/// Stops every kernel, for when the server exits.
+pub async fn shutdown_all() {
+    let sessions = std::mem::take(&mut *SESSIONS.lock().unwrap());
+    for slot in sessions.into_values() {
+        if let Some(session) = slot.session.get() {
+            session.shutdown("the server is shutting down").await;
+        }
+    }
+}
+
+impl Session {
+    /// Starts the kernelspec called `name` (or the default one) and connects to it.
+    async fn start(id: &str, name: Option<&str>, notebook: Option<String>) -> Result<Arc<Self>, String> {
+        let spec = KernelSpec::find(name)
+            .map_err(|e| format!("failed to list kernels: {}", e))?
+            .ok_or_else(|| match name {
+                Some(name) => format!("unknown kernel {}", name),
+                None => "no kernels available".into(),
+            })?;
+        let mut kernel = Kernel::start(&spec)
+            .map_err(|e| format!("failed to start kernel {}: {}", spec.name, e))?;
+        let conn = kernel
+            .connect()
+            .await
+            .map_err(|e| format!("failed to connect to kernel {}: {}", spec.name, e))?;
+
+        let heartbeat = conn.client.heartbeat().map_err(|e| e.to_string())?;
+        let iopub = conn.client.iopub_subscribe().map_err(|e| e.to_string())?;
+        let sent = Arc::clone(&conn.sent);
+
+        let session = Arc::new(Session {
+            id: id.into(),
+            notebook,
+            spec,
+            kernel: Mutex::new(kernel),
+            conn: Mutex::new(conn),
+            started: SystemTime::now(),
+            output: Default::default(),
+            activity: Default::default(),
+            queue: Default::default(),
+            queued: Default::default(),
+            assist: Mutex::new(None),
+            input: Default::default(),
+            stopping: AtomicBool::new(false),
+        });
+        info!("started session {} for {:?}", session.id, session.notebook);
+
+        // Set up the heartbeat watcher
+        std::thread::spawn(move || {
+            for _ in heartbeat {
+                debug!("Received heartbeat from kernel");
+            }
+        });
+
+        // Spawn an IOPub watcher, which stops once the session is gone
+        let weak = Arc::downgrade(&session);
+        std::thread::spawn(move || watch_iopub(weak, iopub, sent));
+
+        tokio::spawn(run_queue(Arc::downgrade(&session), Arc::clone(&session.queued)));
+
+        Ok(session)
+    }
+
+    /// Adds `user` to the clients of this session and replays the updates after `since`, the
+    /// `seq` of the last update a reconnecting client got. Without it, the client gets what was
+    /// sent while nobody was attached.
+    pub fn attach(&self, user: usize, tx: mpsc::UnboundedSender<Message>, since: Option<u64>) {
+        let mut output = self.output.lock().unwrap();
+        if let Some(from) = since.map(|since| since + 1).or(output.unseen) {
+            for (_, message) in output.history.iter().filter(|(seq, _)| *seq >= from) {
+                let _ = tx.send(message.clone());
+            }
+        }
+        output.unseen = None;
+        output.clients.insert(user, tx);
+        output.detached_since = None;
+        info!("user {} attached to session {} ({} clients)", user, self.id, output.clients.len());
+    }
+
+    pub fn detach(&self, user: usize) {
+        let mut output = self.output.lock().unwrap();
+        output.clients.remove(&user);
+        if output.clients.is_empty() {
+            output.detached_since.get_or_insert_with(SystemTime::now);
+        }
+    }
+
+    /// Sends `update` to every attached client with the next `seq`, and keeps it for those
+    /// that reconnect.
+    pub fn send(&self, update: impl Serialize) {
+        let mut update = serde_json::to_value(update).unwrap();
+        let mut output = self.output.lock().unwrap();
+        let seq = output.next_seq;
+        output.next_seq += 1;
+        update["seq"] = seq.into();
+        let message = Message::text(update.to_string());
+
+        // sockets on their way out fail to send, they detach themselves soon enough
+        output.clients.retain(|_, tx| tx.send(message.clone()).is_ok());
+        if output.clients.is_empty() {
+            output.detached_since.get_or_insert_with(SystemTime::now);
+            output.unseen.get_or_insert(seq);
+        }
+        if output.history.len() == HISTORY_LIMIT {
+            output.history.pop_front();
+        }
+        output.history.push_back((seq, message));
+    }
+
+    /// Closes the websockets of all attached clients.
+    fn close(&self) {
+        let mut output = self.output.lock().unwrap();
+        for tx in std::mem::take(&mut output.clients).into_values() {
+            let _ = tx.send(Message::close());
+        }
+        output.detached_since.get_or_insert_with(SystemTime::now);
+    }
+
+    /// What `/kernel/running` shows about this session.
+    pub async fn info(&self) -> RunningKernel {
+        // a restart holds the kernel for a while, the pid is in flux then anyway
+        let pid = self.kernel.try_lock().ok().map(|kernel| kernel.pid());
+        let clients = self.output.lock().unwrap().clients.len();
+        let activity = self.activity.lock().unwrap();
+        RunningKernel {
+            id: self.id.clone(),
+            notebook: self.notebook.clone(),
+            kernelspec: self.spec.to_ref(),
+            pid,
+            started: millis(self.started),
+            execution_state: activity.execution_state.clone(),
+            last_activity: millis(activity.last_activity),
+            clients,
+        }
+    }
+
+    /// Why the culler should shut this session down, if it should.
+    fn cull_reason(&self, limits: &Limits) -> Option<String> {
+        let elapsed = |time: SystemTime| time.elapsed().unwrap_or_default();
+        if let Some(timeout) = limits.detached_timeout {
+            let detached_since = self.output.lock().unwrap().detached_since;
+            if detached_since.is_some_and(|since| elapsed(since) > timeout) {
+                return Some(format!("nobody used the kernel for {} minutes", timeout.as_secs() / 60));
+            }
+        }
+        if let Some(timeout) = limits.idle_timeout {
+            let activity = self.activity.lock().unwrap();
+            // a long running cell is not idle, even if it is quiet
+            if activity.execution_state != "busy" && elapsed(activity.last_activity) > timeout {
+                return Some(format!("the kernel was idle for {} minutes", timeout.as_secs() / 60));
+            }
+        }
+        None
+    }
+
+    /// Adds `run_cell` to the end of the queue and tells the clients its position.
+    pub fn enqueue(&self, run_cell: RunCell) {
+        let position = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.push_back(run_cell.clone());
+            queue.len()
+        };
+        self.send(RunCellUpdate {
+            id: run_cell.id,
+            user: run_cell.user,
+            name: String::from("queued"),
+            value: position.to_string(),
+        });
+        self.send_queue();
+        self.queued.notify_one();
+    }
+
+    /// Drops cell `id` from the queue. Returns `false` if it was not waiting to run.
+    pub fn cancel(&self, id: &str) -> bool {
+        let cancelled = cancel(&mut self.queue.lock().unwrap(), id);
+        if cancelled.is_empty() {
+            return false;
+        }
+        self.drop_cells(cancelled, "cancelled");
+        true
+    }
+
+    /// Moves cell `id` to `position` in the queue, counting from 1, or to the end if the queue is
+    /// shorter. Returns `false` if it was not waiting to run.
+    pub fn move_cell(&self, id: &str, position: usize) -> bool {
+        let moved = match move_cell(&mut self.queue.lock().unwrap(), id, position) {
+            Some(moved) => moved,
+            None => return false,
+        };
+        for (position, run_cell) in moved {
+            self.send(RunCellUpdate {
+                id: run_cell.id,
+                user: run_cell.user,
+                name: String::from("queued"),
+                value: position.to_string(),
+            });
+        }
+        self.send_queue();
+        true
+    }
+
+    /// Drops every cell that has not started yet, telling the clients `reason`.
+    pub fn clear_queue(&self, reason: &str) {
+        let cancelled = std::mem::take(&mut *self.queue.lock().unwrap());
+        self.drop_cells(cancelled, reason);
+    }
+
+    /// Sends the cells waiting to run, in order.
+    pub fn send_queue(&self) {
+        let queue = self
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|run_cell| QueuedCell { id: run_cell.id.clone(), user: run_cell.user })
+            .collect();
+        self.send(KernelUpdate {
+            status: "queue".into(),
+            queue: Some(queue),
+            ..Default::default()
+        });
+    }
+
+    /// Tells the clients that `cancelled` are out of the queue because of `reason`.
+    fn drop_cells(&self, cancelled: VecDeque<RunCell>, reason: &str) {
+        if cancelled.is_empty() {
+            return;
+        }
+        for run_cell in cancelled {
+            self.send(RunCellUpdate {
+                id: run_cell.id,
+                user: run_cell.user,
+                name: String::from("cancelled"),
+                value: reason.into(),
+            });
+        }
+        self.send_queue();
+    }
+
+    /// Runs a cell from the queue and reports how it went.
+    async fn execute(&self, run_cell: RunCell) {
+        let result = {
+            let conn = self.conn.lock().await;
+            // the client blocks until the kernel replies, or is gone
+            let result =
+                tokio::task::block_in_place(|| conn.run_cell(run_cell.clone(), self, || self.kernel_gone()));
+            // an interrupt leaves the last input() unanswered
+            self.input.lock().unwrap().take();
+            result
+        };
+        let failed = match result {
+            Ok(reply) => {
+                self.send(RunCellUpdate {
+                    id: run_cell.id.clone(),
+                    user: run_cell.user,
+                    name: String::from("count"),
+                    value: reply.execution_count.to_string(),
+                });
+                reply.failed
+            }
+            Err(e) => {
+                warn!("session {}: failed to run cell {}: {}", self.id, run_cell.id, e);
+                for (name, value) in [("error", e.to_string()), ("complete", String::new())] {
+                    self.send(RunCellUpdate {
+                        id: run_cell.id.clone(),
+                        user: run_cell.user,
+                        name: name.into(),
+                        value,
+                    });
+                }
+                true
+            }
+        };
+        let dropped = stop_on_error(&mut self.queue.lock().unwrap(), &run_cell, failed);
+        self.drop_cells(dropped, "an earlier cell failed");
+    }
+
+    /// Tab completion for `code` with the cursor at `cursor_pos`.
+    pub async fn complete(&self, code: String, cursor_pos: u64) -> Result<Completion, String> {
+        let content = self.assist("complete_request", json!({ "code": code, "cursor_pos": cursor_pos })).await?;
+        let reply: CompleteReply = serde_json::from_value(content)
+            .map_err(|e| format!("unexpected reply to complete_request: {}", e))?;
+        Ok(Completion {
+            matches: reply.matches,
+            cursor_start: reply.cursor_start,
+            cursor_end: reply.cursor_end,
+        })
+    }
+
+    /// Documentation for whatever is at `cursor_pos` in `code`, with its source if `detail`.
+    pub async fn inspect(&self, code: String, cursor_pos: u64, detail: bool) -> Result<Inspection, String> {
+        let content = json!({ "code": code, "cursor_pos": cursor_pos, "detail_level": u8::from(detail) });
+        let reply: InspectReply = serde_json::from_value(self.assist("inspect_request", content).await?)
+            .map_err(|e| format!("unexpected reply to inspect_request: {}", e))?;
+        Ok(Inspection {
+            found: reply.found,
+            // the text formats, anything else is of no use in a tooltip
+            data: reply
+                .data
+                .into_iter()
+                .filter_map(|(mime, value)| Some((mime, value.as_str()?.to_string())))
+                .collect(),
+        })
+    }
+
+    /// Sends a shell request on a channel of its own and returns the content of the reply.
+    ///
+    /// The kernel handles shell requests one at a time, so while a cell runs the answer would
+    /// only come once it is done. Better no completions than late ones.
+    async fn assist(&self, msg_type: &'static str, content: Value) -> Result<Value, String> {
+        if self.activity.lock().unwrap().execution_state == "busy" {
+            return Err("the kernel is busy".into());
+        }
+        self.touch();
+
+        let mut assist = self.assist.lock().await;
+        let shell = match assist.take() {
+            Some(shell) => shell,
+            None => self.kernel.lock().await.shell().map_err(|e| e.to_string())?,
+        };
+        let (shell, reply) = tokio::task::spawn_blocking(move || {
+            let reply = shell.request(msg_type, &content, ASSIST_TIMEOUT);
+            (shell, reply)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        // a reply that comes in late is skipped by the next request, so the channel can stay
+        *assist = Some(shell);
+        Ok(reply?.content)
+    }
+
+    /// Answers the `input()` that cell `id` is waiting on, if `user` is who it asked.
+    pub fn reply_input(&self, user: usize, id: &str, value: String) -> Result<(), String> {
+        self.touch();
+        match &mut *self.input.lock().unwrap() {
+            Some(input) if input.cell == id && input.user == user && input.line.is_none() => {
+                input.line = Some(value);
+                Ok(())
+            }
+            _ => Err(format!("cell {} is not waiting for input", id)),
+        }
+    }
+
+    /// Whether the kernel is being stopped or has died, see `KernelConnection::run_cell`.
+    fn kernel_gone(&self) -> bool {
+        // whoever holds the kernel is restarting, shutting down or interrupting it. the first
+        // two set `stopping`, the last is over soon enough
+        self.stopping.load(Ordering::SeqCst)
+            || self.kernel.try_lock().is_ok_and(|mut kernel| kernel.has_exited())
+    }
+
+    /// Notes that the session is in use, see `Activity`.
+    pub fn touch(&self) {
+        self.activity.lock().unwrap().last_activity = SystemTime::now();
+    }
+
+    /// Interrupts the running cell and tells the clients.
+    pub async fn interrupt(&self) -> Result<(), String> {
+        self.touch();
+        let result = self
+            .kernel
+            .lock()
+            .await
+            .interrupt()
+            .await
+            .map_err(|e| format!("failed to interrupt kernel: {}", e));
+        self.send(match &result {
+            Ok(()) => KernelUpdate { status: "interrupted".into(), ..Default::default() },
+            Err(message) => KernelUpdate::error(message.clone()),
+        });
+        result
+    }
+
+    /// Restarts the kernel and tells the clients, see `restart_kernel`.
+    pub async fn restart(&self) -> Result<(), String> {
+        self.touch();
+        // what is queued most likely needs variables that are about to be lost
+        self.clear_queue("the kernel restarted");
+        self.send(KernelUpdate { status: "restarting".into(), ..Default::default() });
+        let result = self.restart_kernel().await;
+        self.send(match &result {
+            Ok(()) => KernelUpdate { status: "ready".into(), ..Default::default() },
+            Err(message) => KernelUpdate::error(message.clone()),
+        });
+        result
+    }
+
+    /// Interrupts the running cell, if any, and restarts the kernel once the connection is free.
+    ///
+    /// A kernel that died has nobody left to interrupt, the running cell is aborted instead.
+    async fn restart_kernel(&self) -> Result<(), String> {
+        let mut kernel = self.kernel.lock().await;
+        // a running cell holds the connection until the kernel replies, which it would never do
+        // after the restart
+        if kernel.has_exited() {
+            info!("session {}: kernel {} is gone, starting it again", self.id, self.spec.name);
+            self.stopping.store(true, Ordering::SeqCst);
+        } else {
+            kernel.interrupt().await.map_err(|e| format!("failed to interrupt kernel: {}", e))?;
+        }
+        let conn = tokio::time::timeout(RESTART_TIMEOUT, self.conn.lock()).await;
+        self.stopping.store(false, Ordering::SeqCst);
+        let _conn = conn.map_err(|_| "the running cell did not stop, cannot restart the kernel".to_string())?;
+        kernel.restart().await.map_err(|e| format!("failed to restart kernel: {}", e))
+    }
+
+    /// Stops the kernel and disconnects the clients, telling them `reason`.
+    async fn shutdown(&self, reason: &str) {
+        info!("shutting down session {}: {}", self.id, reason);
+        self.queue.lock().unwrap().clear();
+        self.send(KernelUpdate {
+            status: "shutdown".into(),
+            message: Some(reason.into()),
+            ..Default::default()
+        });
+        self.close();
+
+        self.stopping.store(true, Ordering::SeqCst);
+        let mut kernel = self.kernel.lock().await;
+        if let Err(e) = kernel.shutdown().await {
+            warn!("failed to shut down kernel {}: {}", self.spec.name, e);
+        }
+    }
+}
+
+impl Input for Session {
+    fn ask(&self, run_cell: &RunCell, prompt: &str, password: bool) {
+        *self.input.lock().unwrap() = Some(PendingInput {
+            cell: run_cell.id.clone(),
+            user: run_cell.user,
+            asked: Instant::now(),
+            line: None,
+        });
+        self.send(RunCellUpdate {
+            id: run_cell.id.clone(),
+            user: run_cell.user,
+            name: String::from(if password { "password" } else { "input" }),
+            value: prompt.into(),
+        });
+    }
+
+    fn answer(&self) -> Answer {
+        let mut input = self.input.lock().unwrap();
+        let answer = match input.as_mut() {
+            None => Answer::Gone,
+            Some(PendingInput { line: Some(line), .. }) => Answer::Line(std::mem::take(line)),
+            Some(pending) => {
+                let attached = self.output.lock().unwrap().clients.contains_key(&pending.user);
+                if attached && pending.asked.elapsed() < INPUT_TIMEOUT {
+                    return Answer::Pending;
+                }
+                Answer::Gone
+            }
+        };
+        *input = None;
+        answer
+    }
+}
+
+impl Session {
+    /// Keeps track of the kernel's execution state for `info`.
+    fn record(&self, msg: &Response) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.last_activity = SystemTime::now();
+        if let Response::IoPub(IoPubResponse::Status { content, .. }) = msg {
+            activity.execution_state = format!("{:?}", content.execution_state).to_lowercase();
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default()
+}
+
+/// Takes every entry of cell `id` out of `queue`.
+fn cancel(queue: &mut VecDeque<RunCell>, id: &str) -> VecDeque<RunCell> {
+    let (cancelled, kept) = queue.drain(..).partition(|run_cell| run_cell.id == id);
+    *queue = kept;
+    cancelled
+}
+
+/// Moves cell `id` to `position` in `queue`, see `Session::move_cell`. Returns the cells whose
+/// position changed, with their new one, or `None` if `id` is not queued.
+fn move_cell(queue: &mut VecDeque<RunCell>, id: &str, position: usize) -> Option<Vec<(usize, RunCell)>> {
+    let from = queue.iter().position(|run_cell| run_cell.id == id)?;
+    let run_cell = queue.remove(from)?;
+    let to = position.clamp(1, queue.len() + 1) - 1;
+    queue.insert(to, run_cell);
+    // the cells in between shift by one
+    Some((from.min(to)..=from.max(to)).map(|index| (index + 1, queue[index].clone())).collect())
+}
+
+/// Empties `queue` if `run_cell` failed and asked for the rest not to run. Returns what was dropped.
+fn stop_on_error(queue: &mut VecDeque<RunCell>, run_cell: &RunCell, failed: bool) -> VecDeque<RunCell> {
+    if failed && run_cell.stop_on_error {
+        std::mem::take(queue)
+    } else {
+        VecDeque::new()
+    }
+}
+
+/// Runs queued cells one after another, until the session is gone.
+///
+/// Only holds on to the session while a cell runs, `queued` wakes it when there is work.
+async fn run_queue(session: Weak<Session>, queued: Arc<Notify>) {
+    loop {
+        let next = match session.upgrade() {
+            Some(session) => {
+                let run_cell = session.queue.lock().unwrap().pop_front();
+                run_cell.map(|run_cell| (session, run_cell))
+            }
+            None => break,
+        };
+        match next {
+            Some((session, run_cell)) => {
+                session.send_queue();
+                session.execute(run_cell).await;
+            }
+            None => queued.notified().await,
+        }
+    }
+}
+
+/// Turns kernel output into updates for the cell it belongs to.
+///
+/// Output whose parent is the `execute_request` of a cell in `sent` goes to that cell, however
+/// late it arrives. Output with any other parent goes to the notebook as a whole.
+fn watch_iopub(
+    session: Weak<Session>,
+    receiver: sync::mpsc::Receiver<Response>,
+    sent: Arc<sync::Mutex<SentCells>>,
+) {
+    for msg in receiver {
+        let session = match session.upgrade() {
+            Some(session) => session,
+            None => break,
+        };
+        session.record(&msg);
+        let response = match msg {
+            Response::IoPub(response) => response,
+            _ => continue,
+        };
+
+        let run_cell = parent_id(&response).and_then(|msg_id| sent.lock().unwrap().get(msg_id).cloned());
+        let (name, value) = match update(response) {
+            Some(update) => update,
+            None => continue,
+        };
+        match run_cell {
+            Some(run_cell) => session.send(RunCellUpdate {
+                id: run_cell.id,
+                user: run_cell.user,
+                name: name.into(),
+                value,
+            }),
+            // the kernel also goes busy and idle for requests that are not cells
+            None if name == "complete" || name == "running" => {}
+            None => session.send(NotebookOutput::new(name, value)),
+        }
+    }
+}
+
+fn parent_id(response: &IoPubResponse) -> Option<&str> {
+    match response {
+        IoPubResponse::Status { parent_header, .. }
+        | IoPubResponse::ExecuteInput { parent_header, .. }
+        | IoPubResponse::Stream { parent_header, .. }
+        | IoPubResponse::Error { parent_header, .. }
+        | IoPubResponse::ExecuteResult { parent_header, .. }
+        | IoPubResponse::DisplayData { parent_header, .. } => Some(&parent_header.msg_id),
+        _ => None,
+    }
+}
+
+/// The `name` and `value` of the `RunCellUpdate` for an IOPub message, if clients care about it.
+fn update(response: IoPubResponse) -> Option<(&'static str, String)> {
+    match response {
+        IoPubResponse::Stream { content, .. } => Some(("output", content.text)),
+        IoPubResponse::Error { content, .. } => Some(("error", content.traceback.join("\n"))),
+        IoPubResponse::Status { content, .. } => match content.execution_state {
+            ExecutionState::Idle => Some(("complete", String::new())),
+            _ => None,
+        },
+        IoPubResponse::ExecuteInput { .. } => Some(("running", "*".into())),
+        IoPubResponse::ExecuteResult { content, .. } => {
+            Some(("result", serde_json::to_string(&content.data).unwrap()))
+        }
+        IoPubResponse::DisplayData { content, .. } => {
+            Some(("data", serde_json::to_string(&content.data).unwrap()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_cell(id: &str) -> RunCell {
+        RunCell {
+            id: id.into(),
+            value: String::new(),
+            stop_on_error: false,
+            user: 1,
+        }
+    }
+
+    fn queue(ids: &[&str]) -> VecDeque<RunCell> {
+        ids.iter().map(|id| run_cell(id)).collect()
+    }
+
+    fn ids(queue: &VecDeque<RunCell>) -> Vec<&str> {
+        queue.iter().map(|run_cell| run_cell.id.as_str()).collect()
+    }
+
+    fn positions(moved: Vec<(usize, RunCell)>) -> Vec<(usize, String)> {
+        moved.into_iter().map(|(position, run_cell)| (position, run_cell.id)).collect()
+    }
+
+    #[test]
+    fn move_forward() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        let moved = move_cell(&mut queue, "d", 2).unwrap();
+        assert_eq!(ids(&queue), ["a", "d", "b", "c"]);
+        assert_eq!(positions(moved), [(2, "d".into()), (3, "b".into()), (4, "c".into())]);
+    }
+
+    #[test]
+    fn move_backward() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        let moved = move_cell(&mut queue, "a", 3).unwrap();
+        assert_eq!(ids(&queue), ["b", "c", "a", "d"]);
+        assert_eq!(positions(moved), [(1, "b".into()), (2, "c".into()), (3, "a".into())]);
+    }
+
+    #[test]
+    fn move_in_place() {
+        let mut queue = queue(&["a", "b", "c"]);
+        let moved = move_cell(&mut queue, "b", 2).unwrap();
+        assert_eq!(ids(&queue), ["a", "b", "c"]);
+        assert_eq!(positions(moved), [(2, "b".into())]);
+    }
+
+    #[test]
+    fn move_out_of_range() {
+        let mut queue = queue(&["a", "b", "c"]);
+        // positions count from 1, so 0 is the front
+        move_cell(&mut queue, "c", 0).unwrap();
+        assert_eq!(ids(&queue), ["c", "a", "b"]);
+
+        let moved = move_cell(&mut queue, "c", 99).unwrap();
+        assert_eq!(ids(&queue), ["a", "b", "c"]);
+        assert_eq!(moved.last().map(|(position, _)| *position), Some(3));
+
+        assert!(move_cell(&mut queue, "missing", 1).is_none());
+        assert_eq!(ids(&queue), ["a", "b", "c"]);
+        assert!(move_cell(&mut VecDeque::new(), "a", 1).is_none());
+    }
+
+    #[test]
+    fn cancel_duplicates() {
+        // the same cell can be queued more than once, cancelling drops all of them
+        let mut queue = queue(&["a", "b", "a", "c"]);
+        assert_eq!(ids(&cancel(&mut queue, "a")), ["a", "a"]);
+        assert_eq!(ids(&queue), ["b", "c"]);
+
+        assert!(cancel(&mut queue, "missing").is_empty());
+        assert_eq!(ids(&queue), ["b", "c"]);
+    }
+
+    #[test]
+    fn stop_on_error_clears_the_rest() {
+        let mut queue = queue(&["b", "c"]);
+        let mut failed = run_cell("a");
+        failed.stop_on_error = true;
+
+        assert!(stop_on_error(&mut queue, &failed, false).is_empty());
+        assert!(stop_on_error(&mut queue, &run_cell("a"), true).is_empty());
+        assert_eq!(ids(&queue), ["b", "c"]);
+
+        assert_eq!(ids(&stop_on_error(&mut queue, &failed, true)), ["b", "c"]);
+        assert!(queue.is_empty());
+    }
+}
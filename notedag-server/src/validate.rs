@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::document::{Document, Group};
+
+/// A structural problem found in a notebook.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Issue {
+    /// The file is not a notebook at all
+    #[serde(rename_all = "camelCase")]
+    Unparseable { message: String },
+    /// `root` does not name an existing group
+    #[serde(rename_all = "camelCase")]
+    MissingRoot { root: String },
+    /// `groups[group].cells` references a cell that does not exist
+    #[serde(rename_all = "camelCase")]
+    DanglingCell { group: String, cell: String },
+    /// A cell that no group references
+    #[serde(rename_all = "camelCase")]
+    OrphanedCell { cell: String },
+    /// `groups[group].children` references a group that does not exist
+    #[serde(rename_all = "camelCase")]
+    MissingChild { group: String, child: String },
+    /// `groups[group].nextChild` is not one of its children
+    #[serde(rename_all = "camelCase")]
+    InvalidNextChild { group: String, next_child: String },
+    /// Following `children` from `group` to `child` leads back to an ancestor
+    #[serde(rename_all = "camelCase")]
+    Cycle { group: String, child: String },
+    /// A group listed as a child by more than one group
+    #[serde(rename_all = "camelCase")]
+    MultipleParents { group: String, parents: Vec<String> },
+    /// A group that cannot be reached from the root
+    #[serde(rename_all = "camelCase")]
+    UnreachableGroup { group: String },
+}
+
+/// Name of the group that collects orphaned cells during repair.
+const RECOVERED_GROUP_NAME: &str = "recovered cells";
+
+pub fn validate(doc: &Document) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    let has_root = doc.groups.contains_key(&doc.root);
+    if !has_root {
+        issues.push(Issue::MissingRoot { root: doc.root.clone() });
+    }
+
+    for (id, group) in &doc.groups {
+        for cell in &group.cells {
+            if !doc.cells.contains_key(cell) {
+                issues.push(Issue::DanglingCell { group: id.clone(), cell: cell.clone() });
+            }
+        }
+        for child in &group.children {
+            if !doc.groups.contains_key(child) {
+                issues.push(Issue::MissingChild { group: id.clone(), child: child.clone() });
+            }
+        }
+        if let Some(next_child) = &group.next_child {
+            if !group.children.contains(next_child) {
+                issues.push(Issue::InvalidNextChild {
+                    group: id.clone(),
+                    next_child: next_child.clone(),
+                });
+            }
+        }
+    }
+
+    let referenced: HashSet<&String> = doc.groups.values().flat_map(|group| group.cells.iter()).collect();
+    for id in doc.cells.keys() {
+        if !referenced.contains(id) {
+            issues.push(Issue::OrphanedCell { cell: id.clone() });
+        }
+    }
+
+    for (group, parents) in parents(doc) {
+        if parents.len() > 1 {
+            issues.push(Issue::MultipleParents {
+                group: group.to_string(),
+                parents: parents.into_iter().map(String::from).collect(),
+            });
+        }
+    }
+
+    for (group, child) in back_edges(doc) {
+        issues.push(Issue::Cycle { group: group.to_string(), child: child.to_string() });
+    }
+
+    if has_root {
+        let reachable = reachable(doc, &doc.root);
+        for id in doc.groups.keys() {
+            if !reachable.contains(id.as_str()) {
+                issues.push(Issue::UnreachableGroup { group: id.clone() });
+            }
+        }
+    }
+
+    issues
+}
+
+/// Rewrites `doc` into a well-formed notebook, returning the issues that were fixed.
+///
+/// Nothing is deleted except references to ids that do not exist: orphaned cells are
+/// collected into a new group and unreachable groups are reattached under the root.
+pub fn repair(doc: &mut Document) -> Vec<Issue> {
+    let issues = validate(doc);
+    if issues.is_empty() {
+        return issues;
+    }
+
+    // drop references to things that do not exist
+    let cell_ids: HashSet<String> = doc.cells.keys().cloned().collect();
+    let group_ids: HashSet<String> = doc.groups.keys().cloned().collect();
+    for group in doc.groups.values_mut() {
+        group.cells.retain(|cell| cell_ids.contains(cell));
+        group.children.retain(|child| group_ids.contains(child));
+    }
+
+    if doc.group(&doc.root).is_none() {
+        let candidate = doc
+            .groups
+            .keys()
+            .find(|id| doc.parent(id).is_none())
+            .or_else(|| doc.groups.keys().next())
+            .cloned();
+        doc.root = match candidate {
+            Some(id) => id,
+            None => insert_group(doc, "root"),
+        };
+    }
+
+    // keep only the first edge into every group, which breaks cycles and shared children,
+    // then hang whatever is left over off the root
+    let mut visited = HashSet::new();
+    let root = doc.root.clone();
+    prune_from(doc, &root, &mut visited);
+    let unreachable: Vec<String> = doc.groups.keys().filter(|id| !visited.contains(*id)).cloned().collect();
+    for id in unreachable {
+        if visited.contains(&id) {
+            continue;
+        }
+        root_group(doc).children.push(id.clone());
+        prune_from(doc, &id, &mut visited);
+    }
+
+    for group in doc.groups.values_mut() {
+        let invalid = group.next_child.as_ref().is_some_and(|next| !group.children.contains(next));
+        if invalid {
+            group.next_child = group.children.first().cloned();
+        }
+    }
+
+    let referenced: HashSet<String> = doc.groups.values().flat_map(|group| group.cells.iter().cloned()).collect();
+    let orphans: Vec<String> = doc.cells.keys().filter(|id| !referenced.contains(*id)).cloned().collect();
+    if !orphans.is_empty() {
+        let id = insert_group(doc, RECOVERED_GROUP_NAME);
+        doc.groups.get_mut(&id).unwrap().cells = orphans;
+        root_group(doc).children.push(id);
+    }
+
+    // user state may point at things that were just moved around. the frontend only moves the
+    // focus along the groups it renders, so it has to stay on the active chain
+    let chain: HashSet<String> = doc.active_chain().into_iter().map(|group| group.id.clone()).collect();
+    if doc.ui.focused_group.as_ref().is_some_and(|id| !chain.contains(id)) {
+        doc.ui.focused_group = None;
+    }
+    let focused_cell = doc.ui.focused_cell.as_ref().and_then(|id| doc.cell(id));
+    if focused_cell.and_then(|cell| doc.group_of_cell(&cell.id)).is_none_or(|group| !chain.contains(&group.id)) {
+        doc.ui.focused_cell = None;
+    }
+
+    issues
+}
+
+/// Parents of every group that is listed as a child, in document order.
+fn parents(doc: &Document) -> BTreeMap<&str, Vec<&str>> {
+    let mut parents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (id, group) in &doc.groups {
+        for child in &group.children {
+            if doc.groups.contains_key(child) {
+                parents.entry(child.as_str()).or_default().push(id.as_str());
+            }
+        }
+    }
+    parents
+}
+
+/// Ids of groups reachable from `from` through `children`.
+fn reachable<'a>(doc: &'a Document, from: &'a str) -> HashSet<&'a str> {
+    let mut seen = HashSet::from([from]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(id) = queue.pop_front() {
+        for child in doc.groups.get(id).into_iter().flat_map(|group| group.children.iter()) {
+            if doc.groups.contains_key(child) && seen.insert(child.as_str()) {
+                queue.push_back(child.as_str());
+            }
+        }
+    }
+    seen
+}
+
+/// `(group, child)` edges that close a cycle, found by depth-first search.
+fn back_edges(doc: &Document) -> Vec<(&str, &str)> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        InProgress,
+        Done,
+    }
+
+    let mut marks: BTreeMap<&str, Mark> = BTreeMap::new();
+    let mut edges = vec![];
+    for start in doc.groups.keys() {
+        if marks.contains_key(start.as_str()) {
+            continue;
+        }
+        // stack of (group, index of the next child to visit)
+        let mut stack = vec![(start.as_str(), 0)];
+        marks.insert(start, Mark::InProgress);
+        while let Some((id, idx)) = stack.pop() {
+            let children = &doc.groups[id].children;
+            match children.get(idx) {
+                None => {
+                    marks.insert(id, Mark::Done);
+                }
+                Some(child) => {
+                    stack.push((id, idx + 1));
+                    if !doc.groups.contains_key(child) {
+                        continue;
+                    }
+                    match marks.get(child.as_str()) {
+                        Some(Mark::InProgress) => edges.push((id, child.as_str())),
+                        Some(Mark::Done) => {}
+                        None => {
+                            marks.insert(child, Mark::InProgress);
+                            stack.push((child, 0));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// Breadth-first walk from `from` that removes every edge into an already visited group.
+fn prune_from(doc: &mut Document, from: &str, visited: &mut HashSet<String>) {
+    visited.insert(from.to_string());
+    let mut queue = VecDeque::from([from.to_string()]);
+    while let Some(id) = queue.pop_front() {
+        let group = doc.groups.get_mut(&id).unwrap();
+        group.children.retain(|child| {
+            if visited.insert(child.clone()) {
+                queue.push_back(child.clone());
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+fn root_group(doc: &mut Document) -> &mut Group {
+    doc.groups.get_mut(&doc.root).unwrap()
+}
+
+fn insert_group(doc: &mut Document, name: &str) -> String {
+    let id = Uuid::new_v4().to_string();
+    doc.groups.insert(
+        id.clone(),
+        Group {
+            id: id.clone(),
+            name: name.into(),
+            cells: vec![],
+            children: vec![],
+            next_child: None,
+            extra: Default::default(),
+        },
+    );
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::sample;
+
+    fn group<'a>(doc: &'a mut Document, id: &str) -> &'a mut Group {
+        doc.groups.get_mut(id).unwrap()
+    }
+
+    /// Repairs `doc`, checks that the result is valid and returns what was fixed.
+    fn repaired(doc: &mut Document) -> Vec<Issue> {
+        let issues = repair(doc);
+        assert_eq!(validate(doc), vec![]);
+        issues
+    }
+
+    #[test]
+    fn valid() {
+        let mut doc = sample();
+        assert_eq!(validate(&doc), vec![]);
+        let before = doc.to_json().unwrap();
+        assert_eq!(repair(&mut doc), vec![]);
+        assert_eq!(doc.to_json().unwrap(), before);
+    }
+
+    #[test]
+    fn example_is_valid() {
+        let doc = Document::parse(include_str!("../../tests/refactor_example.ind")).unwrap();
+        assert_eq!(validate(&doc), vec![]);
+    }
+
+    #[test]
+    fn missing_root() {
+        let mut doc = sample();
+        doc.root = "gone".into();
+        assert!(validate(&doc).contains(&Issue::MissingRoot { root: "gone".into() }));
+
+        repaired(&mut doc);
+        // the only group nobody lists as a child
+        assert_eq!(doc.root, "root");
+    }
+
+    #[test]
+    fn missing_root_without_groups() {
+        let mut doc = sample();
+        doc.groups.clear();
+        doc.root = "gone".into();
+        repaired(&mut doc);
+        assert_eq!(doc.group(&doc.root).unwrap().name, "root");
+        // every cell lost its group, and ends up in one for recovered cells
+        let recovered = doc.group_of_cell("c1").unwrap();
+        assert_eq!(recovered.name, RECOVERED_GROUP_NAME);
+        assert_eq!(recovered.cells, ["c1", "c2", "c3"]);
+    }
+
+    #[test]
+    fn dangling_cell() {
+        let mut doc = sample();
+        group(&mut doc, "a").cells.push("gone".into());
+        assert_eq!(validate(&doc), vec![Issue::DanglingCell { group: "a".into(), cell: "gone".into() }]);
+
+        repaired(&mut doc);
+        assert_eq!(doc.group("a").unwrap().cells, ["c2"]);
+    }
+
+    #[test]
+    fn orphaned_cell() {
+        let mut doc = sample();
+        group(&mut doc, "b").cells.clear();
+        assert_eq!(validate(&doc), vec![Issue::OrphanedCell { cell: "c3".into() }]);
+
+        repaired(&mut doc);
+        let recovered = doc.group_of_cell("c3").unwrap();
+        assert_eq!(recovered.name, RECOVERED_GROUP_NAME);
+        assert_eq!(doc.parent(&recovered.id).unwrap().id, "root");
+        // nothing is deleted
+        assert_eq!(doc.cells.len(), 3);
+    }
+
+    #[test]
+    fn missing_child() {
+        let mut doc = sample();
+        group(&mut doc, "a").children.push("gone".into());
+        assert_eq!(validate(&doc), vec![Issue::MissingChild { group: "a".into(), child: "gone".into() }]);
+
+        repaired(&mut doc);
+        assert_eq!(doc.group("a").unwrap().children, ["b"]);
+    }
+
+    #[test]
+    fn invalid_next_child() {
+        let mut doc = sample();
+        group(&mut doc, "root").next_child = Some("b".into());
+        assert_eq!(
+            validate(&doc),
+            vec![Issue::InvalidNextChild { group: "root".into(), next_child: "b".into() }]
+        );
+
+        repaired(&mut doc);
+        assert_eq!(doc.group("root").unwrap().next_child.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn cycle() {
+        let mut doc = sample();
+        group(&mut doc, "b").children.push("a".into());
+        let issues = validate(&doc);
+        assert!(issues.contains(&Issue::Cycle { group: "b".into(), child: "a".into() }));
+        assert!(issues.contains(&Issue::MultipleParents {
+            group: "a".into(),
+            parents: vec!["b".into(), "root".into()],
+        }));
+
+        repaired(&mut doc);
+        assert!(doc.group("b").unwrap().children.is_empty());
+        assert_eq!(doc.parent("a").unwrap().id, "root");
+    }
+
+    #[test]
+    fn multiple_parents() {
+        let mut doc = sample();
+        group(&mut doc, "c").children.push("b".into());
+        assert_eq!(
+            validate(&doc),
+            vec![Issue::MultipleParents { group: "b".into(), parents: vec!["a".into(), "c".into()] }]
+        );
+
+        repaired(&mut doc);
+        // the first edge found from the root is kept
+        assert_eq!(doc.parent("b").unwrap().id, "a");
+        assert!(doc.group("c").unwrap().children.is_empty());
+    }
+
+    #[test]
+    fn unreachable_group() {
+        let mut doc = sample();
+        let mut d = doc.group("c").unwrap().clone();
+        d.id = "d".into();
+        doc.groups.insert("d".into(), d);
+        assert_eq!(validate(&doc), vec![Issue::UnreachableGroup { group: "d".into() }]);
+
+        repaired(&mut doc);
+        assert_eq!(doc.parent("d").unwrap().id, "root");
+    }
+
+    #[test]
+    fn unreachable_cycle() {
+        let mut doc = sample();
+        for (id, child) in [("d", "e"), ("e", "d")] {
+            let mut group = doc.group("c").unwrap().clone();
+            group.id = id.into();
+            group.children = vec![child.into()];
+            doc.groups.insert(id.into(), group);
+        }
+        let issues = validate(&doc);
+        assert!(issues.contains(&Issue::UnreachableGroup { group: "d".into() }));
+        assert!(issues.contains(&Issue::UnreachableGroup { group: "e".into() }));
+        assert!(issues.contains(&Issue::Cycle { group: "e".into(), child: "d".into() }));
+
+        repaired(&mut doc);
+        assert_eq!(doc.parent("d").unwrap().id, "root");
+        assert_eq!(doc.parent("e").unwrap().id, "d");
+    }
+
+    #[test]
+    fn focus_off_the_active_chain() {
+        let mut doc = sample();
+        // the repair points `nextChild` of the root at its first child, away from the focus
+        let root = group(&mut doc, "root");
+        root.children.reverse();
+        root.next_child = Some("gone".into());
+        repaired(&mut doc);
+        assert_eq!(doc.group("root").unwrap().next_child.as_deref(), Some("c"));
+        assert_eq!(doc.ui.focused_group, None);
+        assert_eq!(doc.ui.focused_cell, None);
+    }
+
+    #[test]
+    fn focus_on_the_active_chain_is_kept() {
+        let mut doc = sample();
+        group(&mut doc, "a").cells.push("gone".into());
+        repaired(&mut doc);
+        assert_eq!(doc.ui.focused_group.as_deref(), Some("b"));
+        assert_eq!(doc.ui.focused_cell.as_deref(), Some("c3"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures_util::{Stream, StreamExt};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::sse::Event;
+
+use crate::fsutil;
+use crate::handlers;
+use crate::models::{ListItem, WatchEvent, WatchEventKind};
+use crate::sandbox;
+
+/// How long a move out of the directory may wait for its matching move in.
+const RENAME_WINDOW: Duration = Duration::from_millis(100);
+
+/// Streams changes to the entries of `dir` as server-sent events.
+///
+/// The watcher lives as long as the stream, so it goes away when the client disconnects.
+pub fn watch(dir: &Path) -> notify::Result<impl Stream<Item = Result<Event, Infallible>>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    // moves out of the directory, keyed by rename cookie, that may still turn out to be renames
+    let pending: Arc<Mutex<HashMap<usize, PathBuf>>> = Default::default();
+    let mut watcher = RecommendedWatcher::new(
+        move |result: notify::Result<notify::Event>| {
+            let event = match result {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("watch error: {}", e);
+                    return;
+                }
+            };
+
+            // a rename arrives as `From`, then `To` and `Both` once the entry shows up again.
+            // hold back the `From` so clients see a single rename instead of a delete and a create
+            match (event.kind, event.attrs.tracker()) {
+                (EventKind::Modify(ModifyKind::Name(RenameMode::From)), Some(cookie)) => {
+                    let path = match event.paths.first() {
+                        Some(path) => path.clone(),
+                        None => return,
+                    };
+                    pending.lock().unwrap().insert(cookie, path);
+                    let pending = Arc::clone(&pending);
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        thread::sleep(RENAME_WINDOW);
+                        let path = pending.lock().unwrap().remove(&cookie);
+                        if let Some(path) = path.filter(|path| visible(path)) {
+                            let _ = tx.send(update(WatchEventKind::Delete, &path, None));
+                        }
+                    });
+                    return;
+                }
+                (EventKind::Modify(ModifyKind::Name(RenameMode::To)), Some(cookie))
+                    if pending.lock().unwrap().contains_key(&cookie) =>
+                {
+                    return;
+                }
+                (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), Some(cookie)) => {
+                    pending.lock().unwrap().remove(&cookie);
+                }
+                _ => {}
+            }
+
+            for update in translate(event) {
+                let _ = tx.send(update);
+            }
+        },
+        notify::Config::default(),
+    )?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("watching {:?}", dir);
+
+    let stream = UnboundedReceiverStream::new(rx).map(move |update: WatchEvent| {
+        // keep the watcher alive for as long as someone is listening
+        let _ = &watcher;
+        let event = Event::default().json_data(&update).unwrap_or_else(|e| {
+            warn!("failed to serialize watch event: {}", e);
+            Event::default().comment("dropped event")
+        });
+        Ok(event)
+    });
+    Ok(stream)
+}
+
+/// Maps an inotify (or other backend) event onto what the tree view cares about.
+fn translate(event: notify::Event) -> Vec<WatchEvent> {
+    let paths: Vec<&PathBuf> = event.paths.iter().filter(|path| visible(path)).collect();
+    match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let (from, to) = match event.paths.as_slice() {
+                [from, to] => (from, to),
+                _ => return vec![],
+            };
+            if !visible(to) {
+                return vec![update(WatchEventKind::Delete, from, None)];
+            }
+            // our own atomic saves show up as a temp file replacing the notebook
+            if !visible(from) {
+                return vec![update(WatchEventKind::Modify, to, None)];
+            }
+            vec![update(WatchEventKind::Rename, to, Some(from))]
+        }
+        // moved out of, or into, the watched directory
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            paths.into_iter().map(|path| update(WatchEventKind::Delete, path, None)).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.into_iter().map(|path| update(WatchEventKind::Create, path, None)).collect()
+        }
+        EventKind::Create(_) => paths.into_iter().map(|path| update(WatchEventKind::Create, path, None)).collect(),
+        EventKind::Modify(_) => paths.into_iter().map(|path| update(WatchEventKind::Modify, path, None)).collect(),
+        EventKind::Remove(_) => paths.into_iter().map(|path| update(WatchEventKind::Delete, path, None)).collect(),
+        _ => vec![],
+    }
+}
+
+fn update(kind: WatchEventKind, path: &Path, from: Option<&PathBuf>) -> WatchEvent {
+    let item = match fs::metadata(path) {
+        Ok(metadata) => handlers::list_item(path, &metadata),
+        // deleted, or already gone again by the time we got here
+        Err(_) => ListItem {
+            file_name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            file_path: sandbox::get().relative(path),
+            is_dir: false,
+            size: 0,
+            modified: 0,
+        },
+    };
+    WatchEvent {
+        kind,
+        item,
+        from: from.map(|from| sandbox::get().relative(from)),
+    }
+}
+
+/// Entries the tree view never shows.
+fn visible(path: &Path) -> bool {
+    let root = sandbox::get().root();
+    !fsutil::is_tmp_path(path) && path != root.join(handlers::TRASH_DIR)
+}
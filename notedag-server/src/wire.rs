@@ -0,0 +1,361 @@
+//! Just enough of the Jupyter messaging protocol to send requests to a kernel ourselves.
+//!
+//! `jupyter_client` waits for replies without a timeout, does not tell us the msg_id of what
+//! it sends, and has no stdin channel. We still use it for IOPub and the heartbeat.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Separates the zmq routing prefix from the message frames.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+const PROTOCOL_VERSION: &str = "5.3";
+
+pub type Result<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub msg_id: String,
+    pub session: String,
+    pub username: String,
+    pub date: String,
+    pub msg_type: String,
+    pub version: String,
+}
+
+/// A message from the kernel.
+#[derive(Debug)]
+pub struct Message {
+    pub header: Header,
+    /// msg_id of the request this answers, if any
+    pub parent_id: Option<String>,
+    pub content: Value,
+}
+
+/// A DEALER socket connected to one of the kernel's channels.
+pub struct Channel {
+    socket: zmq::Socket,
+    key: Key,
+    session: String,
+}
+
+/// The HMAC key from the connection file, messages go unsigned if it is empty.
+struct Key(Vec<u8>);
+
+fn context() -> &'static zmq::Context {
+    static CONTEXT: OnceLock<zmq::Context> = OnceLock::new();
+    CONTEXT.get_or_init(zmq::Context::new)
+}
+
+impl Channel {
+    /// Connects to `endpoint`, e.g. `tcp://127.0.0.1:5555`, signing with `key`.
+    ///
+    /// Channels opened with the same `session` look like one client to the kernel, which is
+    /// how it knows where to send the `input_request`s of a cell run on the shell channel.
+    pub fn connect(endpoint: &str, key: &str, session: &str) -> Result<Self> {
+        let socket = context().socket(zmq::DEALER).map_err(|e| e.to_string())?;
+        socket.set_identity(session.as_bytes()).map_err(|e| e.to_string())?;
+        // do not hold up the server on exit with messages the kernel will never read
+        socket.set_linger(0).map_err(|e| e.to_string())?;
+        socket
+            .connect(endpoint)
+            .map_err(|e| format!("failed to connect to {}: {}", endpoint, e))?;
+        Ok(Channel {
+            socket,
+            key: Key(key.as_bytes().to_vec()),
+            session: session.into(),
+        })
+    }
+
+    /// A header for a new message from us.
+    pub fn header(&self, msg_type: &str) -> Header {
+        Header {
+            msg_id: Uuid::new_v4().to_string(),
+            session: self.session.clone(),
+            username: "notedag".into(),
+            date: now(),
+            msg_type: msg_type.into(),
+            version: PROTOCOL_VERSION.into(),
+        }
+    }
+
+    pub fn send(&self, header: &Header, parent: Option<&Header>, content: &Value) -> Result<()> {
+        let frames = self.key.encode(header, parent, content)?;
+        self.socket.send_multipart(frames, 0).map_err(|e| e.to_string())
+    }
+
+    /// Sends a request and waits up to `timeout` for the reply to it.
+    ///
+    /// Replies to earlier requests that timed out are skipped.
+    pub fn request(&self, msg_type: &str, content: &Value, timeout: Duration) -> Result<Message> {
+        let header = self.header(msg_type);
+        self.send(&header, None, content)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.recv(left)? {
+                Some(message) if message.parent_id.as_ref() == Some(&header.msg_id) => return Ok(message),
+                Some(message) => debug!("skipping {} to an earlier request", message.header.msg_type),
+                None => return Err(format!("no reply to {} in time", msg_type)),
+            }
+        }
+    }
+
+    /// The next message, if one arrives within `timeout`.
+    pub fn recv(&self, timeout: Duration) -> Result<Option<Message>> {
+        let ready = self
+            .socket
+            .poll(zmq::POLLIN, timeout.as_millis() as i64)
+            .map_err(|e| e.to_string())?;
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        let frames = self.socket.recv_multipart(0).map_err(|e| e.to_string())?;
+        self.key.parse(&frames).map(Some)
+    }
+}
+
+impl Key {
+    /// The frames of a message, signed.
+    fn encode(&self, header: &Header, parent: Option<&Header>, content: &Value) -> Result<Vec<Vec<u8>>> {
+        let parts = [
+            to_json(header)?,
+            match parent {
+                Some(parent) => to_json(parent)?,
+                None => b"{}".to_vec(),
+            },
+            b"{}".to_vec(),
+            to_json(content)?,
+        ];
+        let signature = self.sign(&parts);
+
+        let mut frames = vec![DELIMITER.to_vec(), signature.into_bytes()];
+        frames.extend(parts);
+        Ok(frames)
+    }
+
+    /// Checks and decodes the frames of a message, as they come off the socket.
+    fn parse(&self, frames: &[Vec<u8>]) -> Result<Message> {
+        let start = frames
+            .iter()
+            .position(|frame| frame == DELIMITER)
+            .ok_or("message without delimiter")?
+            + 1;
+        let (signature, parts) = match frames.get(start..start + 5) {
+            Some([signature, parts @ ..]) => (signature, parts),
+            _ => return Err("truncated message".into()),
+        };
+        if !self.verify(signature, parts) {
+            return Err("message with a bad signature".into());
+        }
+
+        let header: Header = serde_json::from_slice(&parts[0]).map_err(|e| e.to_string())?;
+        let parent: Value = serde_json::from_slice(&parts[1]).map_err(|e| e.to_string())?;
+        let content = serde_json::from_slice(&parts[3]).map_err(|e| e.to_string())?;
+        Ok(Message {
+            header,
+            parent_id: parent.get("msg_id").and_then(Value::as_str).map(String::from),
+            content,
+        })
+    }
+
+    /// HMAC of the header, parent header, metadata and content. Empty if signing is off.
+    fn sign(&self, parts: &[Vec<u8>]) -> String {
+        match self.mac(parts) {
+            Some(mac) => mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect(),
+            None => String::new(),
+        }
+    }
+
+    fn verify(&self, signature: &[u8], parts: &[Vec<u8>]) -> bool {
+        let mac = match self.mac(parts) {
+            Some(mac) => mac,
+            None => return true,
+        };
+        let bytes: Option<Vec<u8>> = signature
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .collect();
+        bytes.is_some_and(|bytes| mac.verify_slice(&bytes).is_ok())
+    }
+
+    fn mac(&self, parts: &[Vec<u8>]) -> Option<Hmac<sha2::Sha256>> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        for part in parts {
+            mac.update(part);
+        }
+        Some(mac)
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| e.to_string())
+}
+
+/// Which of `channels` have a message waiting, after up to `timeout`.
+pub fn poll(channels: &[&Channel], timeout: Duration) -> Result<Vec<bool>> {
+    let mut items: Vec<_> = channels
+        .iter()
+        .map(|channel| channel.socket.as_poll_item(zmq::POLLIN))
+        .collect();
+    zmq::poll(&mut items, timeout.as_millis() as i64).map_err(|e| e.to_string())?;
+    Ok(items.iter().map(|item| item.is_readable()).collect())
+}
+
+/// The current time in ISO 8601, as the protocol wants it in headers.
+fn now() -> String {
+    timestamp(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+/// `since_epoch` in ISO 8601 with microseconds, in UTC.
+fn timestamp(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs() as i64;
+    let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // `civil_from_days` from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(msg_type: &str) -> Header {
+        Header {
+            msg_id: Uuid::new_v4().to_string(),
+            session: "session".into(),
+            username: "notedag".into(),
+            date: now(),
+            msg_type: msg_type.into(),
+            version: PROTOCOL_VERSION.into(),
+        }
+    }
+
+    fn frames(key: &Key) -> (Header, Vec<Vec<u8>>) {
+        let request = header("execute_request");
+        let frames = key.encode(&header("execute_reply"), Some(&request), &json!({ "status": "ok" })).unwrap();
+        (request, frames)
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let key = Key(b"secret".to_vec());
+        let (request, frames) = frames(&key);
+        // hex of a SHA-256 HMAC
+        assert_eq!(frames[1].len(), 64);
+
+        let message = key.parse(&frames).unwrap();
+        assert_eq!(message.header.msg_type, "execute_reply");
+        assert_eq!(message.parent_id, Some(request.msg_id));
+        assert_eq!(message.content, json!({ "status": "ok" }));
+    }
+
+    #[test]
+    fn unsigned_round_trip() {
+        let key = Key(vec![]);
+        let (_, frames) = frames(&key);
+        assert!(frames[1].is_empty());
+        assert!(key.parse(&frames).is_ok());
+    }
+
+    #[test]
+    fn without_parent() {
+        let key = Key(b"secret".to_vec());
+        let frames = key.encode(&header("status"), None, &json!({})).unwrap();
+        assert_eq!(key.parse(&frames).unwrap().parent_id, None);
+    }
+
+    #[test]
+    fn skips_routing_prefix() {
+        let key = Key(b"secret".to_vec());
+        let (_, mut frames) = frames(&key);
+        frames.insert(0, b"identity".to_vec());
+        assert!(key.parse(&frames).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_frames() {
+        let key = Key(b"secret".to_vec());
+        for index in 2..6 {
+            let (_, mut frames) = frames(&key);
+            frames[index].push(b' ');
+            assert_eq!(key.parse(&frames).unwrap_err(), "message with a bad signature", "frame {}", index);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let key = Key(b"secret".to_vec());
+        let (_, mut frames) = frames(&Key(b"other".to_vec()));
+        assert_eq!(key.parse(&frames).unwrap_err(), "message with a bad signature");
+
+        frames[1] = b"not hex".to_vec();
+        assert_eq!(key.parse(&frames).unwrap_err(), "message with a bad signature");
+        frames[1] = vec![];
+        assert_eq!(key.parse(&frames).unwrap_err(), "message with a bad signature");
+    }
+
+    #[test]
+    fn framing_errors() {
+        let key = Key(b"secret".to_vec());
+        let (_, frames) = frames(&key);
+        assert_eq!(key.parse(&frames[1..]).unwrap_err(), "message without delimiter");
+        assert_eq!(key.parse(&frames[..5]).unwrap_err(), "truncated message");
+        assert_eq!(key.parse(&[]).unwrap_err(), "message without delimiter");
+
+        // signed, but not JSON
+        let parts = vec![b"header".to_vec(), b"{}".to_vec(), b"{}".to_vec(), b"{}".to_vec()];
+        let mut frames = vec![DELIMITER.to_vec(), key.sign(&parts).into_bytes()];
+        frames.extend(parts);
+        assert!(key.parse(&frames).is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = |secs: u64, micros: u32| timestamp(Duration::new(secs, micros * 1000));
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(at(946684799, 999999), "1999-12-31T23:59:59.999999Z");
+        assert_eq!(at(1709210096, 789012), "2024-02-29T12:34:56.789012Z");
+    }
+
+    #[test]
+    fn timestamps_on_leap_days() {
+        let at = |secs: u64| timestamp(Duration::from_secs(secs));
+        // divisible by 400, so a leap year
+        assert_eq!(at(951782400), "2000-02-29T00:00:00.000000Z");
+        assert_eq!(at(951782400 + 86400), "2000-03-01T00:00:00.000000Z");
+        // divisible by 100 but not by 400, so not a leap year
+        assert_eq!(at(4107542399), "2100-02-28T23:59:59.000000Z");
+        assert_eq!(at(4107542400), "2100-03-01T00:00:00.000000Z");
+    }
+}